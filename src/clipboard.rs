@@ -0,0 +1,154 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{stdout, Write};
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
+
+// How long text copied out of a sensitive file stays on the clipboard by default
+pub const DEFAULT_CLEAR_SECONDS: u64 = 30;
+
+/*
+    The editor's own clipboard register. Text copied out of a sensitive file is wiped
+    from it after a timeout, and everything on it is wiped when the editor exits, so
+    secrets do not stay around to be pasted somewhere by mistake. With OSC 52 turned
+    on, copies are also sent to the terminal's clipboard and cleared from it at the
+    same times.
+*/
+pub struct Clipboard {
+    text: Option<String>,
+    expires: Option<Instant>,
+    clear_after: Duration,
+    osc52: bool,
+    sent_to_terminal: bool,
+}
+
+impl Clipboard {
+    // Reads the timeout from SFE_CLIPBOARD_SECONDS and turns on OSC 52 when SFE_CLIPBOARD_OSC52 is 1
+    pub fn from_env() -> Clipboard {
+        let seconds = std::env::var("SFE_CLIPBOARD_SECONDS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_CLEAR_SECONDS);
+        Clipboard {
+            text: None,
+            expires: None,
+            clear_after: Duration::from_secs(seconds),
+            osc52: std::env::var("SFE_CLIPBOARD_OSC52").is_ok_and(|v| v.trim() == "1"),
+            sent_to_terminal: false,
+        }
+    }
+
+    pub fn copy(&mut self, text: String, sensitive: bool) {
+        self.wipe();
+        if self.osc52 {
+            self.send_to_terminal(text.as_bytes());
+            self.sent_to_terminal = true;
+        }
+        self.expires = if sensitive {
+            Some(Instant::now() + self.clear_after)
+        } else {
+            None
+        };
+        self.text = Some(text);
+    }
+
+    pub fn paste(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    pub fn clear_after(&self) -> Duration {
+        self.clear_after
+    }
+
+    // Overwrites the text on the clipboard before letting it go, and empties the terminal's clipboard if it was used
+    pub fn clear(&mut self) {
+        self.wipe();
+        if self.sent_to_terminal {
+            self.send_to_terminal(b"");
+            self.sent_to_terminal = false;
+        }
+    }
+
+    // Clears the clipboard once the time for sensitive text is up, returning whether it did
+    pub fn expire(&mut self) -> bool {
+        match self.expires {
+            Some(expires) if Instant::now() >= expires => {
+                self.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn wipe(&mut self) {
+        if let Some(mut text) = self.text.take() {
+            text.zeroize();
+        }
+        self.expires = None;
+    }
+
+    // Sets the terminal's clipboard with an OSC 52 escape sequence; empty data clears it
+    fn send_to_terminal(&self, data: &[u8]) {
+        // Room for all of it up front, so no copy of the text is left behind by growing the string
+        let mut sequence = String::with_capacity(data.len() / 3 * 4 + 16);
+        sequence.push_str("\x1b]52;c;");
+        STANDARD.encode_string(data, &mut sequence);
+        sequence.push('\x07');
+        let mut stdout = stdout();
+        let _ = stdout.write_all(sequence.as_bytes());
+        let _ = stdout.flush();
+        sequence.zeroize();
+    }
+}
+
+impl Drop for Clipboard {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clipboard(clear_after: Duration) -> Clipboard {
+        Clipboard {
+            text: None,
+            expires: None,
+            clear_after,
+            osc52: false,
+            sent_to_terminal: false,
+        }
+    }
+
+    #[test]
+    fn sensitive_text_expires() {
+        let mut clipboard = clipboard(Duration::ZERO);
+        clipboard.copy(String::from("hunter2"), true);
+        assert_eq!(clipboard.paste(), Some("hunter2"));
+        assert!(clipboard.expire());
+        assert_eq!(clipboard.paste(), None);
+        // Once cleared there is nothing left to expire
+        assert!(!clipboard.expire());
+    }
+
+    #[test]
+    fn sensitive_text_stays_until_the_time_is_up() {
+        let mut clipboard = clipboard(Duration::from_secs(3600));
+        clipboard.copy(String::from("hunter2"), true);
+        assert!(!clipboard.expire());
+        assert_eq!(clipboard.paste(), Some("hunter2"));
+    }
+
+    #[test]
+    fn other_text_does_not_expire() {
+        let mut clipboard = clipboard(Duration::ZERO);
+        clipboard.copy(String::from("secret"), true);
+        // Copying text that is not sensitive stops the timer of what was there before
+        clipboard.copy(String::from("plain"), false);
+        assert!(!clipboard.expire());
+        assert_eq!(clipboard.paste(), Some("plain"));
+        clipboard.clear();
+        assert_eq!(clipboard.paste(), None);
+    }
+}
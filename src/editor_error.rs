@@ -0,0 +1,86 @@
+use crate::encryption::CryptoError;
+use crate::file_lock::LockOwner;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/*
+    Everything that can go wrong while reading or writing files. None of these
+    end the session; they are shown on screen and the buffer stays open.
+*/
+#[derive(Debug)]
+pub enum EditorError {
+    NotFound(String),
+    PermissionDenied(String),
+    InvalidUtf8(String),
+    Metadata(String),
+    NotAFile(String),
+    NotADirectory(String),
+    NoHomeDirectory,
+    Locked(String, LockOwner),
+    Unencodable(String, char),
+    Crypto(CryptoError),
+    BadPattern(String, usize, String),
+    BadKey(String, usize),
+    Gpg(String),
+    BadSecret(usize),
+    UnclosedSecret(usize),
+    Io(io::Error),
+}
+
+impl EditorError {
+    // Attaches the path that was being worked on to an io::Error
+    pub fn from_io(path: &str, error: io::Error) -> EditorError {
+        match error.kind() {
+            ErrorKind::NotFound => EditorError::NotFound(path.to_owned()),
+            ErrorKind::PermissionDenied => EditorError::PermissionDenied(path.to_owned()),
+            _ => EditorError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for EditorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditorError::NotFound(p) => write!(f, "{} does not exist", p),
+            EditorError::PermissionDenied(p) => write!(f, "Permission denied: {}", p),
+            EditorError::InvalidUtf8(p) => write!(f, "{} is not valid UTF-8 text", p),
+            EditorError::Metadata(e) => write!(f, "Could not get metadata from file: {}", e),
+            EditorError::NotAFile(p) => write!(f, "{} is not a file", p),
+            EditorError::NotADirectory(p) => write!(f, "{} is not a directory", p),
+            EditorError::NoHomeDirectory => {
+                write!(f, "No home directory to keep autosaves and keys in")
+            }
+            EditorError::Locked(p, owner) => write!(f, "{} is being edited by {}", p, owner),
+            EditorError::Unencodable(encoding, c) => {
+                write!(
+                    f,
+                    "'{}' (U+{:04X}) cannot be saved as {}",
+                    c, *c as u32, encoding
+                )
+            }
+            EditorError::Crypto(e) => write!(f, "{}", e),
+            EditorError::BadPattern(p, line, e) => write!(f, "{} line {}: {}", p, line, e),
+            EditorError::BadKey(p, line) => write!(f, "{} line {}: not an Ed25519 key", p, line),
+            EditorError::Gpg(e) => write!(f, "gpg: {}", e),
+            EditorError::BadSecret(line) => write!(f, "Line {}: encrypted value is damaged", line),
+            EditorError::UnclosedSecret(line) => {
+                write!(f, "Line {}: secret has no closing ⟧", line)
+            }
+            EditorError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EditorError {}
+
+impl From<io::Error> for EditorError {
+    fn from(error: io::Error) -> EditorError {
+        EditorError::Io(error)
+    }
+}
+
+impl From<CryptoError> for EditorError {
+    fn from(error: CryptoError) -> EditorError {
+        EditorError::Crypto(error)
+    }
+}
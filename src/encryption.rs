@@ -0,0 +1,320 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use zeroize::Zeroize;
+
+/*
+    Layout of a passphrase-encrypted file:
+
+        magic      6 bytes   "SFEENC"
+        version    1 byte
+        m_cost     4 bytes   Argon2id memory cost in KiB (little endian)
+        t_cost     4 bytes   Argon2id iterations (little endian)
+        p_cost     4 bytes   Argon2id lanes (little endian)
+        salt      16 bytes
+        nonce     24 bytes
+        ciphertext (XChaCha20-Poly1305, tag appended)
+
+    The whole header is fed to the cipher as associated data, so any tampering
+    with the parameters is caught by the authentication tag.
+*/
+pub const MAGIC: &[u8; 6] = b"SFEENC";
+pub const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_LEN;
+
+// Key derivation settings used for newly encrypted files
+const DEFAULT_M_COST: u32 = 64 * 1024;
+const DEFAULT_T_COST: u32 = 3;
+const DEFAULT_P_COST: u32 = 1;
+// Refuse headers asking for more than 1 GiB so a crafted file cannot exhaust memory
+const MAX_M_COST: u32 = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    NotEncrypted,
+    UnsupportedVersion(u8),
+    Truncated,
+    WrongPassphrase,
+    KeyDerivation(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::NotEncrypted => write!(f, "File is not encrypted"),
+            CryptoError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported encryption format version {}", v)
+            }
+            CryptoError::Truncated => write!(f, "Encrypted file is truncated"),
+            CryptoError::WrongPassphrase => {
+                write!(f, "Wrong passphrase or the file has been tampered with")
+            }
+            CryptoError::KeyDerivation(e) => write!(f, "Could not derive key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+// The parts of the header needed to derive the key and decrypt
+struct Header {
+    nonce: [u8; NONCE_LEN],
+    salt: [u8; SALT_LEN],
+    params: KdfParams,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/*
+    A key derived from the user's passphrase, together with the salt and
+    parameters it was derived with so the same key can be reused for every save.
+*/
+pub struct EncryptionKey {
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+    params: KdfParams,
+}
+
+impl EncryptionKey {
+    // Derives a key for a file that is being encrypted for the first time
+    pub fn new(passphrase: &str) -> Result<EncryptionKey, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let params = KdfParams {
+            m_cost: DEFAULT_M_COST,
+            t_cost: DEFAULT_T_COST,
+            p_cost: DEFAULT_P_COST,
+        };
+        EncryptionKey::derive(passphrase, salt, params)
+    }
+
+    // Derives the key for already encrypted data using the salt and parameters in its header
+    pub fn from_header(passphrase: &str, data: &[u8]) -> Result<EncryptionKey, CryptoError> {
+        let (header, _) = parse_header(data)?;
+        EncryptionKey::derive(passphrase, header.salt, header.params)
+    }
+
+    // Whether a passphrase is the one this key was derived from
+    pub fn matches(&self, passphrase: &str) -> bool {
+        match EncryptionKey::derive(passphrase, self.salt, self.params) {
+            Ok(other) => other.key == self.key,
+            Err(_) => false,
+        }
+    }
+
+    // Whether data was encrypted with a key derived with the same salt and parameters as this one
+    pub fn fits(&self, data: &[u8]) -> bool {
+        match parse_header(data) {
+            Ok((header, _)) => header.salt == self.salt && header.params == self.params,
+            Err(_) => false,
+        }
+    }
+
+    fn derive(
+        passphrase: &str,
+        salt: [u8; SALT_LEN],
+        params: KdfParams,
+    ) -> Result<EncryptionKey, CryptoError> {
+        let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_LEN))
+            .map_err(|e| CryptoError::KeyDerivation(e.to_string()))?;
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| CryptoError::KeyDerivation(e.to_string()))?;
+        Ok(EncryptionKey { key, salt, params })
+    }
+
+    // Encrypts the plaintext under a fresh nonce and prepends the header
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut output = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        output.extend_from_slice(MAGIC);
+        output.push(VERSION);
+        output.extend_from_slice(&self.params.m_cost.to_le_bytes());
+        output.extend_from_slice(&self.params.t_cost.to_le_bytes());
+        output.extend_from_slice(&self.params.p_cost.to_le_bytes());
+        output.extend_from_slice(&self.salt);
+        output.extend_from_slice(&nonce);
+        let ciphertext = XChaCha20Poly1305::new(&self.key.into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &output,
+                },
+            )
+            .expect("XChaCha20-Poly1305 encryption cannot fail for in-memory buffers");
+        output.extend_from_slice(&ciphertext);
+        output
+    }
+
+    // Checks the header and authentication tag, returning the plaintext
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (header, ciphertext) = parse_header(data)?;
+        if header.salt != self.salt || header.params != self.params {
+            return Err(CryptoError::WrongPassphrase);
+        }
+        XChaCha20Poly1305::new(&self.key.into())
+            .decrypt(
+                XNonce::from_slice(&header.nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &data[..HEADER_LEN],
+                },
+            )
+            .map_err(|_| CryptoError::WrongPassphrase)
+    }
+}
+
+// The key lives inside the screen rather than on the heap, so the allocator does not wipe it
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+// Whether the data starts with the encrypted file header
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Splits encrypted data into its header and ciphertext
+fn parse_header(data: &[u8]) -> Result<(Header, &[u8]), CryptoError> {
+    if !is_encrypted(data) {
+        return Err(CryptoError::NotEncrypted);
+    }
+    if data.len() <= MAGIC.len() {
+        return Err(CryptoError::Truncated);
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION {
+        return Err(CryptoError::UnsupportedVersion(version));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(CryptoError::Truncated);
+    }
+    let read_u32 =
+        |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    let params_at = MAGIC.len() + 1;
+    let params = KdfParams {
+        m_cost: read_u32(params_at),
+        t_cost: read_u32(params_at + 4),
+        p_cost: read_u32(params_at + 8),
+    };
+    if params.m_cost > MAX_M_COST {
+        return Err(CryptoError::KeyDerivation(String::from(
+            "memory cost in header is too large",
+        )));
+    }
+    let salt_at = params_at + 12;
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&data[salt_at..salt_at + SALT_LEN]);
+    let nonce_at = salt_at + SALT_LEN;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&data[nonce_at..nonce_at + NONCE_LEN]);
+    Ok((
+        Header {
+            nonce,
+            salt,
+            params,
+        },
+        &data[HEADER_LEN..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"hello world");
+        assert!(is_encrypted(&data));
+        assert_eq!(key.decrypt(&data).unwrap(), b"hello world");
+        // A fresh nonce every time, so the same text never encrypts the same way twice
+        assert_ne!(key.encrypt(b"hello world"), data);
+    }
+
+    #[test]
+    fn key_from_header_decrypts() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"secret");
+        let again = EncryptionKey::from_header("correct horse", &data).unwrap();
+        assert_eq!(again.decrypt(&data).unwrap(), b"secret");
+        assert!(again.fits(&data));
+        assert!(key.matches("correct horse"));
+        assert!(!key.matches("wrong horse"));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"secret");
+        let wrong = EncryptionKey::from_header("wrong horse", &data).unwrap();
+        assert!(matches!(
+            wrong.decrypt(&data),
+            Err(CryptoError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"secret");
+        let mut body = data.clone();
+        *body.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            key.decrypt(&body),
+            Err(CryptoError::WrongPassphrase)
+        ));
+        let mut nonce = data.clone();
+        nonce[HEADER_LEN - 1] ^= 1;
+        assert!(matches!(
+            key.decrypt(&nonce),
+            Err(CryptoError::WrongPassphrase)
+        ));
+        let mut params = data;
+        params[MAGIC.len() + 5] ^= 1;
+        assert!(matches!(
+            key.decrypt(&params),
+            Err(CryptoError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn bad_headers() {
+        let key = EncryptionKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"secret");
+        assert!(matches!(
+            key.decrypt(b"plain text"),
+            Err(CryptoError::NotEncrypted)
+        ));
+        assert!(matches!(
+            key.decrypt(&data[..HEADER_LEN - 1]),
+            Err(CryptoError::Truncated)
+        ));
+        let mut version = data.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            key.decrypt(&version),
+            Err(CryptoError::UnsupportedVersion(_))
+        ));
+        let mut huge = data;
+        huge[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&(MAX_M_COST + 1).to_le_bytes());
+        assert!(matches!(
+            EncryptionKey::from_header("correct horse", &huge),
+            Err(CryptoError::KeyDerivation(_))
+        ));
+    }
+}
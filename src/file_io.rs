@@ -1,118 +1,873 @@
+use crate::editor_error::EditorError;
+use crate::encryption::{self, CryptoError, EncryptionKey};
+use crate::gpg::{self, GpgFile};
+use crate::hex_editor;
 use crate::language::Language;
+use crate::signature::{self, SignatureStatus, TrustedKey};
+use crate::text_buffer::{self, TextBuffer};
+use crate::text_encoding::TextEncoding;
 use chrono::{DateTime, Local};
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, ErrorKind, Read, Write};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use zeroize::Zeroize;
+
+// Makes temporary save files unique within this process
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/*
+    What a file looked like on disk when the editor last read or wrote it, used to
+    notice when another program changes the file underneath an open buffer.
+*/
+#[derive(Clone, PartialEq)]
+pub struct DiskState {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    pub hash: [u8; 32],
+}
+
+// The extended attribute that marks a file as sensitive; it is copied over when the file is saved
+#[cfg(unix)]
+const SENSITIVE_ATTRIBUTE: &str = "user.securefileeditor.sensitive";
+
+// How much of each end of a lazily opened file its disk state is taken from
+const DISK_STATE_SAMPLE_BYTES: usize = 1024 * 1024;
+
+// A file as it is opened: decoded text, raw bytes for the hex view, or a large file read as needed
+pub enum FileContents {
+    Text(String, TextEncoding),
+    Binary(Vec<u8>),
+    Lazy(TextBuffer),
+}
+
+impl FileContents {
+    pub fn from_data(data: Vec<u8>) -> FileContents {
+        if hex_editor::looks_binary(&data) {
+            FileContents::Binary(data)
+        } else {
+            let (text, text_encoding) = TextEncoding::decode_file(&data);
+            FileContents::Text(text, text_encoding)
+        }
+    }
+}
 
 // Deals with all the reading and writing to the file
 pub struct FileIO;
 impl FileIO {
     /* Read from the file */
-    pub fn read_from_file(pathname: &String) -> Result<String, io::Error> {
-        let mut data = String::new();
-        File::open(pathname)?.read_to_string(&mut data)?;
+    pub fn read_from_file(pathname: &String) -> Result<String, EditorError> {
+        String::from_utf8(FileIO::read_bytes_from_file(pathname)?)
+            .map_err(|_| EditorError::InvalidUtf8(pathname.clone()))
+    }
+
+    pub fn read_bytes_from_file(pathname: &String) -> Result<Vec<u8>, EditorError> {
+        let mut data = Vec::new();
+        File::open(pathname)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| EditorError::from_io(pathname, e))?;
         Ok(data)
     }
 
-    pub fn read_from_file_object(mut file: &File) -> Result<String, io::Error> {
+    // Checks whether the file starts with the encrypted file header
+    pub fn is_encrypted_file(pathname: &String) -> bool {
+        let mut header = [0u8; encryption::MAGIC.len()];
+        match File::open(pathname) {
+            Ok(mut file) => match file.read_exact(&mut header) {
+                Ok(_) => encryption::is_encrypted(&header),
+                Err(_) => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Checks whether the file is an encrypted OpenPGP message, which is opened through gpg
+    pub fn is_gpg_file(pathname: &String) -> bool {
+        gpg::is_gpg_data(&FileIO::read_head(pathname))
+    }
+
+    // The first bytes of a file, enough to tell what kind of file it is
+    fn read_head(pathname: &String) -> Vec<u8> {
+        let mut head = Vec::new();
+        if let Ok(file) = File::open(pathname) {
+            let _ = file.take(64).read_to_end(&mut head);
+        }
+        head
+    }
+
+    // Decrypts an OpenPGP file with gpg, as text in its own encoding or as bytes if it is binary
+    pub fn read_gpg_file(pathname: &String) -> Result<(FileContents, GpgFile), EditorError> {
+        let armor = gpg::is_armored(&FileIO::read_head(pathname));
+        let (data, file) = GpgFile::decrypt(pathname, armor)?;
+        Ok((FileContents::from_data(data), file))
+    }
+
+    // Whether the file was marked sensitive, which only systems with extended attributes can do
+    pub fn is_marked_sensitive(pathname: &str) -> bool {
+        #[cfg(unix)]
+        {
+            matches!(xattr::get(pathname, SENSITIVE_ATTRIBUTE), Ok(Some(_)))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = pathname;
+            false
+        }
+    }
+
+    pub fn mark_sensitive(pathname: &str, sensitive: bool) -> Result<(), EditorError> {
+        #[cfg(unix)]
+        {
+            let result = if sensitive {
+                xattr::set(pathname, SENSITIVE_ATTRIBUTE, b"1")
+            } else {
+                xattr::remove(pathname, SENSITIVE_ATTRIBUTE)
+            };
+            result.map_err(|e| EditorError::from_io(pathname, e))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (pathname, sensitive);
+            Err(EditorError::Io(io::Error::from(ErrorKind::Unsupported)))
+        }
+    }
+
+    /* Read and decrypt a passphrase-encrypted file, returning its contents and the key to save it with */
+    pub fn read_from_encrypted_file(
+        pathname: &String,
+        passphrase: &str,
+    ) -> Result<(FileContents, EncryptionKey), EditorError> {
+        let data = FileIO::read_bytes_from_file(pathname)?;
+        let key = EncryptionKey::from_header(passphrase, &data)?;
+        let contents = FileContents::from_data(key.decrypt(&data)?);
+        Ok((contents, key))
+    }
+
+    // Reads one of the editor's own UTF-8 files, decrypting it with the given key when it is encrypted
+    pub fn read_with_key(
+        pathname: &String,
+        key: Option<&EncryptionKey>,
+    ) -> Result<String, EditorError> {
+        String::from_utf8(FileIO::read_plaintext(pathname, key)?)
+            .map_err(|_| EditorError::InvalidUtf8(pathname.clone()))
+    }
+
+    // Reads a user's file as text in its own encoding, or as bytes if it is binary
+    // Encrypted files are decrypted with the given key
+    pub fn read_decoded(
+        pathname: &String,
+        key: Option<&EncryptionKey>,
+    ) -> Result<FileContents, EditorError> {
+        if let Some(buffer) = FileIO::open_lazily(pathname)? {
+            return Ok(FileContents::Lazy(buffer));
+        }
+        Ok(FileContents::from_data(FileIO::read_plaintext(
+            pathname, key,
+        )?))
+    }
+
+    // Large plain text files are not read up front, so the first screen shows straight away
+    fn open_lazily(pathname: &String) -> Result<Option<TextBuffer>, EditorError> {
+        let file = File::open(pathname).map_err(|e| EditorError::from_io(pathname, e))?;
+        let len = file
+            .metadata()
+            .map_err(|e| EditorError::from_io(pathname, e))?
+            .len();
+        if len < text_buffer::LAZY_LOAD_BYTES || FileIO::is_encrypted_file(pathname) {
+            return Ok(None);
+        }
+        TextBuffer::open_lazily(file).map_err(|e| EditorError::from_io(pathname, e))
+    }
+
+    fn read_plaintext(
+        pathname: &String,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Vec<u8>, EditorError> {
+        let data = FileIO::read_bytes_from_file(pathname)?;
+        if encryption::is_encrypted(&data) {
+            match key {
+                Some(k) => Ok(k.decrypt(&data)?),
+                None => Err(EditorError::Crypto(CryptoError::WrongPassphrase)),
+            }
+        } else {
+            Ok(data)
+        }
+    }
+
+    /*
+        Returns None when the file does not exist. Files big enough to be opened lazily
+        are not read in full; their hash only covers the size and the first and last
+        megabyte, and the timestamp catches changes in between.
+    */
+    pub fn get_disk_state(pathname: &String) -> Result<Option<DiskState>, EditorError> {
+        let metadata = match fs::metadata(pathname) {
+            Ok(m) => m,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(EditorError::from_io(pathname, e)),
+        };
+        let hash = if metadata.len() >= text_buffer::LAZY_LOAD_BYTES {
+            FileIO::sample_hash(pathname, metadata.len())
+        } else {
+            fs::read(pathname).map(|data| Sha256::digest(&data).into())
+        };
+        Ok(Some(DiskState {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: hash.map_err(|e| EditorError::from_io(pathname, e))?,
+        }))
+    }
+
+    fn sample_hash(pathname: &String, len: u64) -> Result<[u8; 32], io::Error> {
+        let mut file = File::open(pathname)?;
+        let mut hasher = Sha256::new();
+        hasher.update(len.to_le_bytes());
+        let mut sample = vec![0; DISK_STATE_SAMPLE_BYTES];
+        file.read_exact(&mut sample)?;
+        hasher.update(&sample);
+        file.seek(SeekFrom::End(-(DISK_STATE_SAMPLE_BYTES as i64)))?;
+        file.read_exact(&mut sample)?;
+        hasher.update(&sample);
+        Ok(hasher.finalize().into())
+    }
+
+    // Cheap check of the timestamp and size, which avoids hashing the file when nothing changed
+    pub fn disk_stamp_matches(pathname: &String, known: &DiskState) -> bool {
+        match fs::metadata(pathname) {
+            Ok(metadata) => {
+                metadata.modified().ok() == known.modified && metadata.len() == known.len
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn read_from_file_object(mut file: &File) -> Result<String, EditorError> {
         let mut output = String::new();
         file.read_to_string(&mut output)?;
         Ok(output)
     }
 
     // Gets the file at the given location, returns None if it does not exist
-    pub fn get_file(file_path: &String) -> Option<File> {
-        let f = File::open(file_path);
-        match f {
-            Ok(file) => Some(file),
+    pub fn get_file(file_path: &String) -> Result<Option<File>, EditorError> {
+        match File::open(file_path) {
+            Ok(file) => Ok(Some(file)),
             Err(error) => match error.kind() {
-                ErrorKind::NotFound => None,
-                other_error => {
-                    panic!("Problem opening the file: {:?}", other_error)
-                }
+                ErrorKind::NotFound => Ok(None),
+                _ => Err(EditorError::from_io(file_path, error)),
             },
         }
     }
 
-    pub fn create_file(file_path: &String) -> File {
-        match File::create(file_path) {
-            Ok(fc) => fc,
-            Err(e) => panic!("Problem creating the file: {:?}", e),
-        }
+    pub fn create_file(file_path: &String) -> Result<File, EditorError> {
+        File::create(file_path).map_err(|e| EditorError::from_io(file_path, e))
     }
 
-    pub fn append_to_file(pathname: &String, new_text: &String) -> Result<bool, io::Error> {
+    pub fn append_to_file(pathname: &String, new_text: &String) -> Result<bool, EditorError> {
         let mut file = OpenOptions::new()
-            .write(true)
             .append(true)
             .open(pathname)
-            .unwrap();
+            .map_err(|e| EditorError::from_io(pathname, e))?;
         write!(file, "{}", new_text)?;
         Ok(true)
     }
 
-    pub fn overwrite_to_file(pathname: &String, new_text: &String) -> Result<bool, io::Error> {
-        FileIO::create_file(pathname); // If applied to a file that exists it wipes the file contents
-        FileIO::append_to_file(pathname, new_text)
+    pub fn overwrite_to_file(pathname: &String, new_text: &String) -> Result<bool, EditorError> {
+        FileIO::overwrite_bytes_to_file(pathname, new_text.as_bytes())
+    }
+
+    /*
+        Replaces the file in one step so a crash or a full disk never leaves it half written.
+        The data goes to a temporary file in the same directory, which gets the original
+        file's permissions, owner and extended attributes, is flushed to disk and is then
+        renamed over the target. If any step fails the temporary file is removed and the
+        original is left untouched. Files that cannot be replaced that way are written in
+        place instead (see write_in_place).
+    */
+    pub fn overwrite_bytes_to_file(pathname: &String, data: &[u8]) -> Result<bool, EditorError> {
+        FileIO::overwrite_file_with(pathname, |file| file.write_all(data))
     }
 
-    pub fn auto_save(pathname: &Option<String>, current_state_of_text: &String) {
-        let pathname: String = {
-            match pathname {
-                Some(s) => FileIO::get_auto_save_path(s),
-                None => String::from(""),
+    /*
+        Saves a text buffer as it is, without putting all of a lazily opened file in
+        memory first. A lazily opened buffer then reads from the file it was saved as.
+    */
+    pub fn overwrite_buffer_to_file(
+        pathname: &String,
+        buffer: &mut TextBuffer,
+    ) -> Result<bool, EditorError> {
+        FileIO::overwrite_file_with(pathname, |file| {
+            let mut output = io::BufWriter::new(file);
+            buffer.write_to(&mut output)?;
+            output.flush()
+        })?;
+        if buffer.is_lazy() {
+            File::open(pathname)
+                .and_then(|file| buffer.rebase(file))
+                .map_err(|e| EditorError::from_io(pathname, e))?;
+        }
+        Ok(true)
+    }
+
+    // Does the work of overwrite_bytes_to_file, with the data written by a function
+    fn overwrite_file_with(
+        pathname: &String,
+        write: impl FnOnce(&mut File) -> Result<(), io::Error>,
+    ) -> Result<bool, EditorError> {
+        // Saving through a symlink replaces the file it points to, not the link
+        let target: PathBuf = match fs::canonicalize(pathname) {
+            Ok(p) => p,
+            Err(e) if e.kind() == ErrorKind::NotFound => PathBuf::from(pathname),
+            Err(e) => return Err(EditorError::from_io(pathname, e)),
+        };
+        let original = match fs::metadata(&target) {
+            Ok(m) => Some(m),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(EditorError::from_io(pathname, e)),
+        };
+        if let Some(metadata) = &original {
+            // Renaming would otherwise replace files the user is not allowed to write
+            if metadata.permissions().readonly() {
+                return Err(EditorError::PermissionDenied(format!(
+                    "{} is read only",
+                    pathname
+                )));
             }
+        }
+        let directory = match target.parent() {
+            Some(d) if d != Path::new("") => d.to_path_buf(),
+            _ => PathBuf::from("."),
         };
-        let result = FileIO::overwrite_to_file(&pathname, current_state_of_text);
-        match result {
-            Ok(_f) => {
-                println!("Autosaved");
+        let file_name = match target.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => return Err(EditorError::NotAFile(pathname.clone())),
+        };
+        let temp_path = directory.join(format!(
+            ".{}.{}.{}.tmp",
+            file_name,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Replacing a file with other hard links would split it from them
+        if original.as_ref().is_some_and(FileIO::has_other_links) {
+            return FileIO::write_in_place(&target, write).map(|_| true);
+        }
+        let file = match FileIO::create_temp_file(&temp_path, &original) {
+            Ok(f) => f,
+            /*
+                The directory does not let the user create files, or the file belongs to
+                someone else (a shared, group-writable file) and the temporary file could
+                not be given to them. Either way the file can still be written in place.
+            */
+            Err(e) if e.kind() == ErrorKind::PermissionDenied && original.is_some() => {
+                let _ = fs::remove_file(&temp_path);
+                return FileIO::write_in_place(&target, write).map(|_| true);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(EditorError::from_io(pathname, e));
             }
+        };
+        let result = FileIO::write_temp_file(file, write, &temp_path, &target, &original)
+            .and_then(|_| fs::rename(&temp_path, &target))
+            .and_then(|_| FileIO::sync_directory(&directory));
+        match result {
+            Ok(_) => Ok(true),
             Err(e) => {
-                eprintln!("There was an error autosaving: {}", e)
+                let _ = fs::remove_file(&temp_path);
+                Err(EditorError::from_io(pathname, e))
             }
         }
     }
 
-    pub fn get_auto_save_path(pathname: &String) -> String {
-        format!("{}~", pathname)
+    // Creates the temporary file, already owned by the owner of the file it will replace
+    fn create_temp_file(
+        temp_path: &Path,
+        original: &Option<fs::Metadata>,
+    ) -> Result<File, io::Error> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // Nobody else can read the data before the original permissions are applied
+            options.mode(if original.is_some() { 0o600 } else { 0o666 });
+        }
+        let file = options.open(temp_path)?;
+        #[cfg(unix)]
+        if let Some(metadata) = original {
+            use std::os::unix::fs::MetadataExt;
+            std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+        Ok(file)
     }
 
-    pub fn delete_auto_save(pathname: &String) {
-        FileIO::delete_file(&FileIO::get_auto_save_path(pathname));
+    // Writes and flushes the temporary file, copying over the metadata of the file it will replace
+    fn write_temp_file(
+        mut file: File,
+        write: impl FnOnce(&mut File) -> Result<(), io::Error>,
+        temp_path: &Path,
+        target: &Path,
+        original: &Option<fs::Metadata>,
+    ) -> Result<(), io::Error> {
+        write(&mut file)?;
+        if let Some(metadata) = original {
+            #[cfg(unix)]
+            FileIO::copy_extended_attributes(target, temp_path)?;
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()
     }
 
-    pub fn check_for_auto_save(pathname: &String) -> bool {
-        match FileIO::get_file(&FileIO::get_auto_save_path(pathname)) {
-            Some(_f) => true,
-            None => false,
-        }
+    #[cfg(unix)]
+    fn has_other_links(metadata: &fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() > 1
     }
 
-    pub fn delete_file(pathname: &String) {
-        let result = fs::remove_file(pathname);
+    #[cfg(not(unix))]
+    fn has_other_links(_metadata: &fs::Metadata) -> bool {
+        false
+    }
+
+    /*
+        Overwrites the file itself, keeping its owner, links and attributes, for when it
+        cannot be replaced. The new data is written out and flushed to a staging file
+        in the user's private directory first, so a lazily opened buffer still reads
+        the old file while it is saved, and a copy of the old contents is kept beside
+        it. Space for the new data is reserved before the file is touched, and if
+        copying it in fails anyway the old contents are put back.
+    */
+    fn write_in_place(
+        target: &Path,
+        write: impl FnOnce(&mut File) -> Result<(), io::Error>,
+    ) -> Result<(), EditorError> {
+        let pathname = target.to_string_lossy().into_owned();
+        let directory = FileIO::auto_save_directory()?;
+        let name = format!(
+            "{}.{}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let new_path = directory.join(format!("{}.new", name));
+        let old_path = directory.join(format!("{}.old", name));
+        let result = FileIO::copy_in_place(target, write, &new_path, &old_path);
+        let _ = fs::remove_file(&new_path);
         match result {
-            Ok(_f) => {
-                println!("File deleted");
+            Ok(_) => {
+                let _ = fs::remove_file(&old_path);
+                Ok(())
             }
-            Err(e) => {
-                eprintln!("Error deleting file: {}", e);
+            // The old contents could not be put back, so their copy is all that is left of them
+            Err((e, false)) => Err(EditorError::Io(io::Error::new(
+                e.kind(),
+                format!(
+                    "{}: {}; its old contents were kept in {}",
+                    pathname,
+                    e,
+                    old_path.display()
+                ),
+            ))),
+            Err((e, true)) => {
+                let _ = fs::remove_file(&old_path);
+                Err(EditorError::from_io(&pathname, e))
             }
         }
     }
 
-    pub fn get_metadata(pathname: &String) -> String {
-        let file = FileIO::get_file(&pathname).unwrap();
+    // Does the work of write_in_place; errors come with whether the target still holds its old contents
+    fn copy_in_place(
+        target: &Path,
+        write: impl FnOnce(&mut File) -> Result<(), io::Error>,
+        new_path: &Path,
+        old_path: &Path,
+    ) -> Result<(), (io::Error, bool)> {
+        let unchanged = |e| (e, true);
+        let mut new = FileIO::create_staging_file(new_path).map_err(unchanged)?;
+        write(&mut new)
+            .and_then(|_| new.sync_all())
+            .map_err(unchanged)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(target)
+            .map_err(unchanged)?;
+        let mut old = FileIO::create_staging_file(old_path).map_err(unchanged)?;
+        io::copy(&mut file, &mut old)
+            .and_then(|_| old.sync_all())
+            .and_then(|_| new.metadata())
+            .and_then(|m| FileIO::reserve_space(&file, m.len()))
+            .map_err(unchanged)?;
+        if let Err(e) = FileIO::copy_over(&mut new, &mut file) {
+            return Err((e, FileIO::copy_over(&mut old, &mut file).is_ok()));
+        }
+        Ok(())
+    }
+
+    // A file only the user can read, for write_in_place to keep data in
+    fn create_staging_file(path: &Path) -> Result<File, io::Error> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)
+    }
+
+    // Replaces all of one file's contents with another's and flushes it
+    fn copy_over(from: &mut File, to: &mut File) -> Result<(), io::Error> {
+        from.rewind()?;
+        to.rewind()?;
+        let length = io::copy(from, to)?;
+        to.set_len(length)?;
+        to.sync_all()
+    }
+
+    // Makes sure the disk has room for the file to grow to the given length before it is overwritten
+    #[cfg(target_os = "linux")]
+    fn reserve_space(file: &File, length: u64) -> Result<(), io::Error> {
+        use std::os::unix::io::AsRawFd;
+        let result = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+        match result {
+            // Some filesystems cannot reserve space, and an empty file needs none
+            0 | libc::EOPNOTSUPP | libc::EINVAL => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn reserve_space(_file: &File, _length: u64) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn copy_extended_attributes(from: &Path, to: &Path) -> Result<(), io::Error> {
+        let names = match xattr::list(from) {
+            Ok(n) => n,
+            // Filesystems without extended attributes have nothing to copy
+            Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for name in names {
+            if let Some(value) = xattr::get(from, &name)? {
+                match xattr::set(to, &name, &value) {
+                    Ok(_) => {}
+                    // Security labels are managed by the system and may not be settable by users
+                    Err(_) if !name.to_string_lossy().starts_with("user.") => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the rename itself durable
+    fn sync_directory(directory: &Path) -> Result<(), io::Error> {
+        #[cfg(unix)]
+        {
+            File::open(directory)?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = directory;
+        }
+        Ok(())
+    }
+
+    pub fn overwrite_to_encrypted_file(
+        pathname: &String,
+        data: &[u8],
+        key: &EncryptionKey,
+    ) -> Result<bool, EditorError> {
+        FileIO::overwrite_bytes_to_file(pathname, &key.encrypt(data))
+    }
+
+    pub fn overwrite_to_gpg_file(
+        pathname: &String,
+        data: &[u8],
+        file: &GpgFile,
+    ) -> Result<bool, EditorError> {
+        FileIO::overwrite_bytes_to_file(pathname, &file.encrypt(data)?)
+    }
+
+    /*
+        Autosaves live in a per-user directory that only the user can open, named after a
+        hash of the file's canonical path. Buffers with a key are autosaved encrypted.
+    */
+    pub fn auto_save(
+        pathname: &Option<String>,
+        current_state_of_text: &String,
+        key: Option<&EncryptionKey>,
+    ) -> Result<bool, EditorError> {
+        let auto_save_path = FileIO::get_auto_save_path(pathname)?;
+        FileIO::write_private_file(&auto_save_path, current_state_of_text.as_bytes(), key)?;
+        Ok(true)
+    }
+
+    // Writes one of the editor's own files that only the user may read, encrypted when there is a key
+    fn write_private_file(
+        path: &Path,
+        plaintext: &[u8],
+        key: Option<&EncryptionKey>,
+    ) -> Result<(), EditorError> {
+        let data = match key {
+            Some(k) => k.encrypt(plaintext),
+            None => plaintext.to_vec(),
+        };
+        let path = path.to_string_lossy().into_owned();
+        FileIO::overwrite_bytes_to_file(&path, &data)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+                .map_err(|e| EditorError::from_io(&path, e))?;
+        }
+        Ok(())
+    }
+
+    // Where the undo history of the given file is kept, next to its autosave
+    pub fn get_undo_path(pathname: &Option<String>) -> Result<PathBuf, EditorError> {
+        Ok(FileIO::get_auto_save_path(pathname)?.with_extension("undo"))
+    }
+
+    // Keeps a file's undo history, encrypted with the file's key if it has one
+    pub fn save_undo_history(
+        pathname: &Option<String>,
+        history: &[u8],
+        key: Option<&EncryptionKey>,
+    ) -> Result<(), EditorError> {
+        FileIO::write_private_file(&FileIO::get_undo_path(pathname)?, history, key)
+    }
+
+    pub fn read_undo_history(
+        pathname: &Option<String>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Vec<u8>, EditorError> {
+        let undo_path = FileIO::get_undo_path(pathname)?;
+        FileIO::read_plaintext(&undo_path.to_string_lossy().into_owned(), key)
+    }
+
+    pub fn delete_undo_history(pathname: &Option<String>) {
+        if let Ok(path) = FileIO::get_undo_path(pathname) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    // Reads an autosave back, decrypting it with the buffer's key if it was encrypted
+    pub fn read_auto_save(
+        pathname: &Option<String>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<String, EditorError> {
+        let auto_save_path = FileIO::get_auto_save_path(pathname)?;
+        FileIO::read_with_key(&auto_save_path.to_string_lossy().into_owned(), key)
+    }
+
+    // Where the autosave for the given file (or for an unnamed buffer, when None) is kept
+    pub fn get_auto_save_path(pathname: &Option<String>) -> Result<PathBuf, EditorError> {
+        let identity: String = match pathname {
+            Some(p) => match fs::canonicalize(p) {
+                Ok(c) => c.to_string_lossy().into_owned(),
+                Err(_) => std::env::current_dir()?
+                    .join(p)
+                    .to_string_lossy()
+                    .into_owned(),
+            },
+            // Unnamed buffers are recovered when the editor is started again in the same directory
+            None => format!(
+                "untitled:{}",
+                fs::canonicalize(std::env::current_dir()?)?.to_string_lossy()
+            ),
+        };
+        let hash: String = Sha256::digest(identity.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(FileIO::auto_save_directory()?.join(format!("{}.autosave", hash)))
+    }
+
+    // The per-user autosave directory, created with permissions only the user can use
+    fn auto_save_directory() -> Result<PathBuf, EditorError> {
+        let base = match std::env::var_os("XDG_STATE_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => match std::env::var_os("HOME").or_else(|| std::env::var_os("LOCALAPPDATA")) {
+                Some(h) => PathBuf::from(h).join(".local").join("state"),
+                None => return Err(EditorError::NoHomeDirectory),
+            },
+        };
+        let directory = base.join("securefileeditor").join("autosave");
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&directory)?;
+        if !fs::symlink_metadata(&directory)?.is_dir() {
+            return Err(EditorError::NotADirectory(directory.display().to_string()));
+        }
+        #[cfg(unix)]
+        {
+            // The directory may have been created earlier with looser permissions
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&directory, fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(directory)
+    }
+
+    // When the autosave was last written, formatted for display
+    pub fn get_auto_save_time(pathname: &Option<String>) -> Option<String> {
+        FileIO::get_modified_time(&FileIO::get_auto_save_path(pathname).ok()?)
+    }
+
+    pub fn get_modified_time(path: &Path) -> Option<String> {
+        let modified: DateTime<Local> = fs::metadata(path).ok()?.modified().ok()?.into();
+        Some(format!("{}", modified.format("%T on %m/%d/%Y")))
+    }
+
+    pub fn delete_auto_save(pathname: &Option<String>) {
+        if let Ok(path) = FileIO::get_auto_save_path(pathname) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    pub fn check_for_auto_save(pathname: &Option<String>) -> bool {
+        match FileIO::get_auto_save_path(pathname) {
+            Ok(path) => path.exists(),
+            Err(_) => false,
+        }
+    }
+
+    /*
+        Looks for a detached signature next to the file and checks it against the file on
+        disk and the user's trusted keys. Only a broken trusted keys file is an error;
+        anything wrong with the signature itself makes it invalid.
+    */
+    pub fn verify_signature(pathname: &String) -> Result<SignatureStatus, EditorError> {
+        let signature_path = signature::signature_path(pathname);
+        if !Path::new(&signature_path).exists() {
+            return Ok(SignatureStatus::Unsigned);
+        }
+        let signed_by = match (
+            FileIO::read_from_file(&signature_path),
+            FileIO::read_bytes_from_file(pathname),
+        ) {
+            (Ok(line), Ok(data)) => signature::check(&data, &line),
+            _ => Err(SignatureStatus::Invalid),
+        };
+        match signed_by {
+            Ok(key) => Ok(signature::trust(&key, &FileIO::read_trusted_keys()?)),
+            Err(status) => Ok(status),
+        }
+    }
+
+    // Signs the file as it is on disk, replacing its detached signature
+    pub fn sign_file(pathname: &String, key: &SigningKey) -> Result<(), EditorError> {
+        let data = FileIO::read_bytes_from_file(pathname)?;
+        FileIO::overwrite_bytes_to_file(
+            &signature::signature_path(pathname),
+            signature::sign(&data, key).as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    // The keys in the user's trusted keys file, or none if there is no such file
+    fn read_trusted_keys() -> Result<Vec<TrustedKey>, EditorError> {
+        let path = FileIO::config_directory()?.join(signature::TRUSTED_KEYS_FILE);
+        let shown = path.display().to_string();
+        let text = match FileIO::read_from_file(&shown) {
+            Ok(t) => t,
+            Err(EditorError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        signature::parse_trusted_keys(&text).map_err(|line| EditorError::BadKey(shown, line))
+    }
+
+    // The user's key for signing files on save, or None if they do not have one yet
+    pub fn read_signing_key() -> Result<Option<SigningKey>, EditorError> {
+        let path = FileIO::config_directory()?.join(signature::SIGNING_KEY_FILE);
+        let shown = path.display().to_string();
+        let text = match FileIO::read_from_file(&shown) {
+            Ok(t) => t,
+            Err(EditorError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match signature::parse_signing_key(text) {
+            Some(key) => Ok(Some(key)),
+            None => Err(EditorError::BadKey(shown, 1)),
+        }
+    }
+
+    // Creates a signing key that only the user can read, returning it with the path it was written to
+    pub fn create_signing_key() -> Result<(SigningKey, String), EditorError> {
+        let directory = FileIO::config_directory()?;
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&directory)?;
+        let path = directory.join(signature::SIGNING_KEY_FILE);
+        let shown = path.display().to_string();
+        let mut options = OpenOptions::new();
+        // Never replaces a key that files may already have been signed with
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let (key, mut text) = signature::new_signing_key();
+        let written = options
+            .open(&path)
+            .and_then(|mut f| f.write_all(text.as_bytes()));
+        text.zeroize();
+        written.map_err(|e| EditorError::from_io(&shown, e))?;
+        Ok((key, shown))
+    }
+
+    // The per-user directory for the editor's keys
+    fn config_directory() -> Result<PathBuf, EditorError> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => match std::env::var_os("HOME").or_else(|| std::env::var_os("APPDATA")) {
+                Some(h) => PathBuf::from(h).join(".config"),
+                None => return Err(EditorError::NoHomeDirectory),
+            },
+        };
+        Ok(base.join("securefileeditor"))
+    }
+
+    pub fn delete_file(pathname: &String) -> Result<(), EditorError> {
+        fs::remove_file(pathname).map_err(|e| EditorError::from_io(pathname, e))
+    }
+
+    pub fn get_metadata(pathname: &String) -> Result<String, EditorError> {
+        let file = match FileIO::get_file(pathname)? {
+            Some(f) => f,
+            None => return Err(EditorError::NotFound(pathname.clone())),
+        };
         let metadata = match file.metadata() {
-            Err(e) => panic!("Could not get metadata from file: {}", e),
+            Err(e) => return Err(EditorError::Metadata(e.to_string())),
             Ok(f) => f,
         };
 
-        let mut temp: DateTime<Local> = metadata.accessed().unwrap().into();
-        let accessed: String = format!("{}", temp.format("%T on %m/%d/%Y"));
-        temp = metadata.created().unwrap().into();
-        let created: String = format!("{}", temp.format("%T on %m/%d/%Y"));
-        temp = metadata.modified().unwrap().into();
-        let modified: String = format!("{}", temp.format("%T on %m/%d/%Y"));
+        // Not every filesystem records every timestamp
+        let format_time = |time: io::Result<std::time::SystemTime>| match time {
+            Ok(t) => {
+                let temp: DateTime<Local> = t.into();
+                format!("{}", temp.format("%T on %m/%d/%Y"))
+            }
+            Err(_) => String::from("unavailable"),
+        };
+        let accessed: String = format_time(metadata.accessed());
+        let created: String = format_time(metadata.created());
+        let modified: String = format_time(metadata.modified());
         let mut file_text = String::new();
         let mut file_type = String::new();
         for a in pathname.chars() {
@@ -127,69 +882,48 @@ impl FileIO {
             "File name: {}\nFile type: {}\nLast accessed: {}\nCreated: {}\nLast Modified: {}\nLength: {} characters\nPermissions:   {}",
             file_text, file_type, accessed, created, modified, metadata.len(), if metadata.permissions().readonly() {"Read only"} else {"Writeable"}
         );
-        output
+        Ok(output)
     }
 
-    pub fn get_file_contents(path: &Option<String>) -> String {
+    // Decodes the opened file, new files start out as UTF-8
+    pub fn get_file_contents(path: &Option<String>) -> Result<FileContents, EditorError> {
         match path {
-            Some(f) => {
-                let test = FileIO::read_from_file(&f);
-                match test {
-                    Ok(f) => String::from(f),
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        panic!("ERROR");
-                    }
-                }
-            }
-            None => String::new(),
+            Some(f) => FileIO::read_decoded(f, None),
+            None => Ok(FileContents::Text(String::new(), TextEncoding::default())),
         }
     }
 
     // If the user is working on a saved file, it will hold the path to the target file
     // If the user is working on an unsaved file, it will hold None
-    pub fn get_file_path(args: std::env::Args) -> Option<String> {
+    pub fn get_file_path(args: std::env::Args) -> Result<Option<String>, EditorError> {
         let inputs: Vec<String> = args.collect();
         if inputs.len() >= 2 {
             let file_path = &inputs[1];
-            match FileIO::get_file(file_path) {
-                Some(_f) => {
-                    // If the user uses the autosave, it replaces the current save with the auto save
-                    // If the user does not use the autosave, it simply ignores the autosave
-                    if FileIO::check_for_auto_save(file_path) {
-                        println!("Use autosave?");
-                        let mut line = String::new();
-                        std::io::stdin().read_line(&mut line).unwrap();
-                        println!("{}", line.trim());
-                        if line.trim().eq("y") || line.trim().eq("yes") {
-                            FileIO::overwrite_to_file(
-                                file_path,
-                                &FileIO::read_from_file(&FileIO::get_auto_save_path(file_path))
-                                    .unwrap(),
-                            )
-                            .unwrap();
-                            FileIO::delete_auto_save(file_path);
-                        }
-                    }
-                    Some(String::from(file_path))
-                }
-                None => None,
+            match FileIO::get_file(file_path)? {
+                Some(_f) => Ok(Some(String::from(file_path))),
+                None => Ok(None),
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
-    pub fn get_highlights(file_type: String) -> Option<Language> {
+    // Missing highlighting.txt just means there is no highlighting
+    pub fn get_highlights(file_type: String) -> Result<Option<Language>, EditorError> {
         if file_type == "" {
-            return None;
+            return Ok(None);
         }
-        let lines: Vec<String> = FileIO::get_file_contents(&Some(String::from("highlighting.txt")))
+        let highlights = match FileIO::read_from_file(&String::from("highlighting.txt")) {
+            Ok(h) => h,
+            Err(EditorError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let lines: Vec<String> = highlights
             .split("\n")
             .map(|x| x.trim().to_owned())
             .collect();
         if lines.len() == 0 {
-            return None;
+            return Ok(None);
         }
         for i in 0..lines.len() {
             if match lines[i].split_once(" ") {
@@ -215,10 +949,68 @@ impl FileIO {
                         related += "\n";
                         n += 1;
                     }
-                    return Some(Language::new(related));
+                    return Ok(Some(Language::new(related)));
                 }
             }
         }
-        None
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two names for the same file in the temporary directory, removed again when dropped
+    struct LinkedFiles(PathBuf, PathBuf);
+
+    impl LinkedFiles {
+        fn new(name: &str, text: &str) -> LinkedFiles {
+            let directory = std::env::temp_dir();
+            let path = directory.join(format!("sfe-{}-{}.txt", name, std::process::id()));
+            let link = directory.join(format!("sfe-{}-link-{}.txt", name, std::process::id()));
+            fs::write(&path, text).unwrap();
+            fs::hard_link(&path, &link).unwrap();
+            LinkedFiles(path, link)
+        }
+    }
+
+    impl Drop for LinkedFiles {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(&self.1);
+        }
+    }
+
+    /*
+        A file with other hard links is written in place, while a lazily opened
+        buffer of it still has chunks to read from it that an edit near the start
+        would push the new text over.
+    */
+    #[test]
+    fn lazy_buffer_is_saved_in_place_over_hard_links() {
+        let text: String = (0..20_000).map(|n| format!("line {}\n", n)).collect();
+        let files = LinkedFiles::new("lazy-linked", &text);
+        let mut buffer = TextBuffer::open_lazily(File::open(&files.0).unwrap())
+            .unwrap()
+            .unwrap();
+        buffer.insert(0, "an early insertion\n");
+        let expected = format!("an early insertion\n{}", text);
+        let pathname = files.0.to_string_lossy().into_owned();
+        FileIO::overwrite_buffer_to_file(&pathname, &mut buffer).unwrap();
+        assert_eq!(fs::read_to_string(&files.0).unwrap(), expected);
+        assert_eq!(fs::read_to_string(&files.1).unwrap(), expected);
+        // The buffer reads the saved file from now on
+        assert!(buffer.is_lazy());
+        assert_eq!(buffer.to_string(), expected);
+        assert!(!buffer.is_damaged());
+    }
+
+    #[test]
+    fn bytes_are_saved_in_place_over_hard_links() {
+        let files = LinkedFiles::new("bytes-linked", "a longer old text\n");
+        let pathname = files.0.to_string_lossy().into_owned();
+        FileIO::overwrite_bytes_to_file(&pathname, b"new\n").unwrap();
+        assert_eq!(fs::read(&files.1).unwrap(), b"new\n");
     }
 }
@@ -0,0 +1,273 @@
+use crate::editor_error::EditorError;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// A lock file that was replaced while we waited for it is retried this many times
+const ACQUIRE_ATTEMPTS: usize = 3;
+
+// Who holds the lock on a file, as written into its lock file
+#[derive(Clone, PartialEq, Debug)]
+pub struct LockOwner {
+    pub user: String,
+    pub pid: u32,
+    pub host: String,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        LockOwner {
+            user: current_user(),
+            pid: std::process::id(),
+            host: current_host(),
+        }
+    }
+
+    // Lock files hold the user, PID and host on a line each
+    fn parse(record: &str) -> Option<LockOwner> {
+        let mut fields = record.lines();
+        let user = fields.next()?.to_owned();
+        let pid = fields.next()?.trim().parse().ok()?;
+        let host = fields.next()?.to_owned();
+        Some(LockOwner { user, pid, host })
+    }
+
+    fn record(&self) -> String {
+        format!("{}\n{}\n{}\n", self.user, self.pid, self.host)
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (PID {} on {})", self.user, self.pid, self.host)
+    }
+}
+
+pub enum LockStatus {
+    Acquired(FileLock),
+    HeldBy(LockOwner),
+}
+
+/*
+    An advisory lock on an open file, held through a lock file next to it. The lock
+    file is kept open with an exclusive OS lock for as long as the editor runs, so
+    the lock goes away by itself when the holder dies and a later instance can take
+    it over, while the text inside only says who the holder is.
+*/
+pub struct FileLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileLock {
+    // The lock file for a file: ".name.sfe-lock" in the same directory
+    pub fn lock_path(pathname: &String) -> Result<PathBuf, EditorError> {
+        // Every link to a file has to agree on the lock
+        let target = match fs::canonicalize(pathname) {
+            Ok(p) => p,
+            Err(e) if e.kind() == ErrorKind::NotFound => PathBuf::from(pathname),
+            Err(e) => return Err(EditorError::from_io(pathname, e)),
+        };
+        let directory = match target.parent() {
+            Some(d) if d != Path::new("") => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        match target.file_name() {
+            Some(n) => Ok(directory.join(format!(".{}.sfe-lock", n.to_string_lossy()))),
+            None => Err(EditorError::NotAFile(pathname.clone())),
+        }
+    }
+
+    // Takes the lock on a file, or reports who already has it
+    pub fn acquire(pathname: &String) -> Result<LockStatus, EditorError> {
+        let path = FileLock::lock_path(pathname)?;
+        let display = path.to_string_lossy().into_owned();
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create(true).truncate(false);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                // Only the user's own sessions need to read who holds the lock
+                options.mode(0o600);
+            }
+            let mut file = options
+                .open(&path)
+                .map_err(|e| EditorError::from_io(&display, e))?;
+            match file.try_lock() {
+                Ok(_) => {}
+                Err(TryLockError::WouldBlock) => {
+                    return Ok(LockStatus::HeldBy(FileLock::read_owner(&mut file)));
+                }
+                Err(TryLockError::Error(e)) => return Err(EditorError::from_io(&display, e)),
+            }
+            // The previous holder may have removed the lock file while we were opening it
+            if !FileLock::still_linked(&file, &path) {
+                continue;
+            }
+            let owner = LockOwner::current();
+            file.set_len(0)
+                .and_then(|_| file.write_all(owner.record().as_bytes()))
+                .and_then(|_| file.sync_all())
+                .map_err(|e| EditorError::from_io(&display, e))?;
+            return Ok(LockStatus::Acquired(FileLock { path, file }));
+        }
+        Err(EditorError::Io(std::io::Error::other(format!(
+            "{} keeps changing, could not lock it",
+            display
+        ))))
+    }
+
+    // Whether this lock covers the given file
+    pub fn covers(&self, pathname: &String) -> bool {
+        match FileLock::lock_path(pathname) {
+            Ok(p) => p == self.path,
+            Err(_) => false,
+        }
+    }
+
+    fn read_owner(file: &mut File) -> LockOwner {
+        let mut record = String::new();
+        let _ = file.seek(SeekFrom::Start(0));
+        let _ = file.read_to_string(&mut record);
+        // The holder may not have written its details yet
+        LockOwner::parse(&record).unwrap_or(LockOwner {
+            user: String::from("another editor"),
+            pid: 0,
+            host: String::from("an unknown host"),
+        })
+    }
+
+    #[cfg(unix)]
+    fn still_linked(file: &File, path: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+            _ => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn still_linked(_file: &File, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+impl Drop for FileLock {
+    // The lock file is removed while still locked, so nobody can take over a file that is going away
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+fn current_user() -> String {
+    match std::env::var("USER").or_else(|_| std::env::var("LOGNAME")) {
+        Ok(u) if !u.is_empty() => u,
+        _ => {
+            #[cfg(unix)]
+            {
+                // SAFETY: getuid has no preconditions and cannot fail
+                format!("uid {}", unsafe { libc::getuid() })
+            }
+            #[cfg(not(unix))]
+            {
+                String::from("unknown")
+            }
+        }
+    }
+}
+
+fn current_host() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0u8; 256];
+        // SAFETY: the buffer is valid for its whole length and gethostname writes at most that much
+        let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+        if result == 0 {
+            let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+            return String::from_utf8_lossy(&buffer[..end]).into_owned();
+        }
+    }
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| String::from("localhost"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file to lock in its own directory, removed again when dropped
+    struct Locked(PathBuf);
+
+    impl Locked {
+        fn new(name: &str) -> Locked {
+            let directory =
+                std::env::temp_dir().join(format!("sfe-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join("notes.txt"), "text").unwrap();
+            Locked(directory)
+        }
+
+        fn file(&self) -> String {
+            self.0.join("notes.txt").to_string_lossy().into_owned()
+        }
+
+        fn lock_file(&self) -> PathBuf {
+            self.0.join(".notes.txt.sfe-lock")
+        }
+    }
+
+    impl Drop for Locked {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn acquiring_writes_the_owner() {
+        let locked = Locked::new("lock-acquire");
+        let lock = match FileLock::acquire(&locked.file()).unwrap() {
+            LockStatus::Acquired(lock) => lock,
+            LockStatus::HeldBy(owner) => panic!("held by {}", owner),
+        };
+        assert!(lock.covers(&locked.file()));
+        let record = fs::read_to_string(locked.lock_file()).unwrap();
+        assert_eq!(LockOwner::parse(&record), Some(LockOwner::current()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(locked.lock_file())
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        drop(lock);
+        assert!(!locked.lock_file().exists());
+    }
+
+    #[test]
+    fn another_session_holding_the_lock_is_reported() {
+        let locked = Locked::new("lock-held");
+        let _lock = FileLock::acquire(&locked.file()).unwrap();
+        // Every open of the lock file is a session of its own to the OS lock
+        match FileLock::acquire(&locked.file()).unwrap() {
+            LockStatus::HeldBy(owner) => assert_eq!(owner, LockOwner::current()),
+            LockStatus::Acquired(_) => panic!("the lock was taken twice"),
+        }
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let locked = Locked::new("lock-stale");
+        // Left behind by an editor that died without removing it
+        fs::write(locked.lock_file(), "someone\n1\nelsewhere\n").unwrap();
+        let lock = FileLock::acquire(&locked.file()).unwrap();
+        assert!(matches!(lock, LockStatus::Acquired(_)));
+        let record = fs::read_to_string(locked.lock_file()).unwrap();
+        assert_eq!(LockOwner::parse(&record), Some(LockOwner::current()));
+    }
+}
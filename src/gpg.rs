@@ -0,0 +1,328 @@
+use crate::editor_error::EditorError;
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use std::io::{stdout, Write};
+use std::process::{Command, Output, Stdio};
+
+// Names of files that are encrypted with gpg when they are saved for the first time
+pub const EXTENSIONS: [&str; 3] = ["gpg", "pgp", "asc"];
+
+// The first line of an ASCII-armored OpenPGP message
+const ARMOR_HEADER: &[u8] = b"-----BEGIN PGP MESSAGE-----";
+
+// Who a file is encrypted to, so it can be encrypted to the same people again
+#[derive(PartialEq, Debug)]
+pub enum Recipients {
+    // The key IDs the file names, as gpg reported them while decrypting it
+    Keys(Vec<String>),
+    // Encrypted with a passphrase rather than to keys
+    Passphrase,
+    // A new file, encrypted to the user's own default key
+    DefaultKey,
+}
+
+/*
+    An OpenPGP encrypted file that is decrypted and encrypted by the local gpg
+    binary. The plaintext only ever passes through pipes to and from gpg, never
+    a temporary file, and passphrases are asked for by gpg itself.
+*/
+pub struct GpgFile {
+    recipients: Recipients,
+    armor: bool,
+}
+
+impl GpgFile {
+    // A file that does not exist yet, encrypted to the user's default key when it is first saved
+    pub fn new_file(pathname: &str) -> GpgFile {
+        GpgFile {
+            recipients: Recipients::DefaultKey,
+            armor: pathname.ends_with(".asc"),
+        }
+    }
+
+    // Decrypts the file at pathname, remembering who it was encrypted to and whether it was armored
+    pub fn decrypt(pathname: &str, armor: bool) -> Result<(Vec<u8>, GpgFile), EditorError> {
+        let output = with_terminal(|| {
+            gpg()
+                .args(["--batch", "--status-fd", "2", "--decrypt", "--", pathname])
+                .stdin(Stdio::null())
+                .output()
+        })
+        .map_err(|e| EditorError::Gpg(e.to_string()))?;
+        let status = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(failure(&status, "decryption failed"));
+        }
+        let recipients = recipients_from_status(&status);
+        Ok((output.stdout, GpgFile { recipients, armor }))
+    }
+
+    // Encrypts the plaintext to the same recipients, and in the same form, as the file was
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, EditorError> {
+        let args = self.encrypt_args()?;
+        let run = || -> std::io::Result<Output> {
+            let mut child = gpg()
+                .args(&args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            let mut stdin = child.stdin.take().unwrap();
+            // Written from another thread so gpg never blocks on a full output pipe
+            std::thread::scope(|scope| {
+                scope.spawn(move || {
+                    let _ = stdin.write_all(plaintext);
+                });
+                child.wait_with_output()
+            })
+        };
+        // Only a passphrase has to be typed; encrypting to keys needs nothing from the user
+        let output = match self.recipients {
+            Recipients::Passphrase => with_terminal(run),
+            _ => run(),
+        }
+        .map_err(|e| EditorError::Gpg(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failure(
+                &String::from_utf8_lossy(&output.stderr),
+                "encryption failed",
+            ));
+        }
+        Ok(output.stdout)
+    }
+
+    /*
+        The arguments gpg encrypts the file with. The key IDs a file names are often
+        those of encryption subkeys, which gpg looks up as the keys they belong to.
+        In batch mode gpg refuses keys the user has not certified as trusted, but the
+        file was encrypted to these keys before, so their trust is not asked about.
+    */
+    fn encrypt_args(&self) -> Result<Vec<&str>, EditorError> {
+        let mut args: Vec<&str> = vec!["--batch", "--yes"];
+        if self.armor {
+            args.push("--armor");
+        }
+        match &self.recipients {
+            Recipients::Keys(keys) => {
+                // gpg --throw-keyids leaves the recipients out of the file
+                if keys.iter().any(|k| k.chars().all(|c| c == '0')) {
+                    return Err(EditorError::Gpg(String::from(
+                        "the file does not name who it is encrypted to, so it cannot be encrypted again",
+                    )));
+                }
+                args.extend(["--trust-model", "always", "--encrypt"]);
+                for key in keys {
+                    args.push("--recipient");
+                    args.push(key);
+                }
+            }
+            Recipients::Passphrase => args.push("--symmetric"),
+            Recipients::DefaultKey => args.extend(["--encrypt", "--default-recipient-self"]),
+        }
+        Ok(args)
+    }
+}
+
+// Who a file was encrypted to, from the status lines gpg printed while decrypting it
+fn recipients_from_status(status: &str) -> Recipients {
+    let keys: Vec<String> = status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] ENC_TO "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(String::from)
+        .collect();
+    if keys.is_empty() {
+        Recipients::Passphrase
+    } else {
+        Recipients::Keys(keys)
+    }
+}
+
+// Whether data starts like an encrypted OpenPGP message, armored or binary
+pub fn is_gpg_data(head: &[u8]) -> bool {
+    head.starts_with(ARMOR_HEADER) || starts_with_session_key(head).unwrap_or(false)
+}
+
+// Whether the data is an ASCII-armored message rather than a binary one
+pub fn is_armored(head: &[u8]) -> bool {
+    head.starts_with(ARMOR_HEADER)
+}
+
+/*
+    Encrypted binary messages start with a packet holding the session key, either
+    encrypted to a public key (tag 1) or derived from a passphrase (tag 3). The
+    version byte after the packet header is checked too, so that other binary
+    files starting with the same byte are not handed to gpg.
+*/
+fn starts_with_session_key(head: &[u8]) -> Option<bool> {
+    let first = *head.first()?;
+    let (tag, header_len) = if first & 0xC0 == 0xC0 {
+        let length_len = match *head.get(1)? {
+            0..=191 => 1,
+            192..=223 => 2,
+            255 => 5,
+            _ => return Some(false),
+        };
+        (first & 0x3F, 1 + length_len)
+    } else if first & 0x80 == 0x80 {
+        let length_len = match first & 0x03 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            _ => return Some(false),
+        };
+        ((first >> 2) & 0x0F, 1 + length_len)
+    } else {
+        return Some(false);
+    };
+    let version = *head.get(header_len)?;
+    Some(match tag {
+        1 => matches!(version, 3 | 6),
+        3 => matches!(version, 4..=6),
+        _ => false,
+    })
+}
+
+// The gpg command, told which terminal to ask for passphrases on
+fn gpg() -> Command {
+    let mut command = Command::new("gpg");
+    #[cfg(unix)]
+    if std::env::var_os("GPG_TTY").is_none() {
+        if let Some(tty) = terminal_name() {
+            command.env("GPG_TTY", tty);
+        }
+    }
+    command
+}
+
+#[cfg(unix)]
+fn terminal_name() -> Option<String> {
+    unsafe {
+        let name = libc::ttyname(0);
+        if name.is_null() {
+            None
+        } else {
+            Some(
+                std::ffi::CStr::from_ptr(name)
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+/*
+    Lets gpg's passphrase prompt use the terminal while f runs. Before the editor
+    has set up the terminal there is nothing to give back; afterwards raw mode is
+    turned off and the normal screen shown until gpg is done.
+*/
+fn with_terminal<T>(f: impl FnOnce() -> T) -> T {
+    let raw = terminal::is_raw_mode_enabled().unwrap_or(false);
+    if raw {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+    }
+    let result = f();
+    if raw {
+        let _ = execute!(stdout(), EnterAlternateScreen);
+        let _ = terminal::enable_raw_mode();
+    }
+    result
+}
+
+// The last message gpg printed that is not a status line, or the fallback if there is none
+fn failure(stderr: &str, fallback: &str) -> EditorError {
+    let message = stderr
+        .lines()
+        .rev()
+        .find(|line| !line.starts_with("[GNUPG:]") && !line.trim().is_empty())
+        .map(|line| line.trim_start_matches("gpg: ").to_owned())
+        .unwrap_or(String::from(fallback));
+    EditorError::Gpg(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_key_packets() {
+        // Old format packets: a public-key session key (tag 1) and a passphrase one (tag 3)
+        assert_eq!(
+            starts_with_session_key(&[0x85, 0x01, 0x0c, 0x03]),
+            Some(true)
+        );
+        assert_eq!(starts_with_session_key(&[0x8c, 0x0d, 0x04]), Some(true));
+        // New format packets, with one and two length bytes
+        assert_eq!(starts_with_session_key(&[0xc1, 0x5e, 0x03]), Some(true));
+        assert_eq!(
+            starts_with_session_key(&[0xc3, 0xc0, 0x10, 0x05]),
+            Some(true)
+        );
+        assert_eq!(
+            starts_with_session_key(&[0xc1, 0xff, 0, 0, 1, 0, 0x06]),
+            Some(true)
+        );
+        // The right tag with a version no session key packet has
+        assert_eq!(
+            starts_with_session_key(&[0x85, 0x01, 0x0c, 0x04]),
+            Some(false)
+        );
+        assert_eq!(starts_with_session_key(&[0x8c, 0x0d, 0x02]), Some(false));
+        // Other packets, and files that are not packets at all
+        assert_eq!(starts_with_session_key(&[0xa3, 0x01, 0x03]), Some(false));
+        assert_eq!(starts_with_session_key(b"text"), Some(false));
+        assert_eq!(starts_with_session_key(&[0xc1, 0xe0, 0x03]), Some(false));
+        // Too short to tell
+        assert_eq!(starts_with_session_key(&[]), None);
+        assert_eq!(starts_with_session_key(&[0x85, 0x01]), None);
+        assert!(!is_gpg_data(&[0x85, 0x01]));
+        assert!(is_gpg_data(b"-----BEGIN PGP MESSAGE-----\n"));
+    }
+
+    #[test]
+    fn recipients_are_read_from_status_lines() {
+        let status = "[GNUPG:] ENC_TO 1A2B3C4D5E6F7081 18 0\n\
+                      gpg: encrypted with 3072-bit RSA key, ID 1A2B3C4D5E6F7081\n\
+                      [GNUPG:] ENC_TO 0123456789ABCDEF 1 0\n\
+                      [GNUPG:] DECRYPTION_OKAY\n";
+        assert_eq!(
+            recipients_from_status(status),
+            Recipients::Keys(vec![
+                String::from("1A2B3C4D5E6F7081"),
+                String::from("0123456789ABCDEF")
+            ])
+        );
+        assert_eq!(
+            recipients_from_status("[GNUPG:] NEED_PASSPHRASE_SYM 9 3 2\n"),
+            Recipients::Passphrase
+        );
+    }
+
+    #[test]
+    fn files_are_encrypted_to_the_keys_they_name() {
+        let file = GpgFile {
+            recipients: Recipients::Keys(vec![String::from("1A2B3C4D5E6F7081")]),
+            armor: true,
+        };
+        assert_eq!(
+            file.encrypt_args().unwrap(),
+            [
+                "--batch",
+                "--yes",
+                "--armor",
+                "--trust-model",
+                "always",
+                "--encrypt",
+                "--recipient",
+                "1A2B3C4D5E6F7081"
+            ]
+        );
+        // Written with --throw-keyids
+        let file = GpgFile {
+            recipients: Recipients::Keys(vec![String::from("0000000000000000")]),
+            armor: false,
+        };
+        assert!(file.encrypt_args().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::mem::size_of;
+use std::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+
+// Set once locking the editor's memory was tried and did not work
+static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/*
+    The allocator for the whole editor. It wipes every block before handing it back
+    to the system, so the text of a file does not outlive the buffers, search strings
+    and copies it passed through. Growing a block goes through alloc and dealloc
+    (the default realloc), so the old block is wiped when the data moves as well.
+*/
+pub struct ZeroizingAllocator;
+
+unsafe impl GlobalAlloc for ZeroizingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        wipe(ptr, layout.size());
+        System.dealloc(ptr, layout)
+    }
+}
+
+/*
+    Zeroes memory with volatile writes, which are not optimized away as stores to
+    memory that is about to be freed. Most of it is written a word at a time, since
+    key derivation alone frees 64 MiB every time a passphrase is entered.
+*/
+unsafe fn wipe(ptr: *mut u8, size: usize) {
+    let head = ptr.align_offset(size_of::<usize>()).min(size);
+    let words = (size - head) / size_of::<usize>();
+    for i in 0..head {
+        ptr.add(i).write_volatile(0);
+    }
+    let word_ptr = ptr.add(head).cast::<usize>();
+    for i in 0..words {
+        word_ptr.add(i).write_volatile(0);
+    }
+    for i in head + words * size_of::<usize>()..size {
+        ptr.add(i).write_volatile(0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/*
+    Keeps the editor's memory out of core dumps and away from debuggers: no core
+    file is written if the editor crashes, and on Linux other processes of the same
+    user cannot attach to it with ptrace or read its memory through /proc.
+*/
+pub fn harden_process() {
+    #[cfg(unix)]
+    unsafe {
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0);
+    }
+}
+
+/*
+    Stops the editor's memory from being written to swap where that is possible.
+    Memory is only locked on Linux and when the limit on locked memory can be raised
+    to unlimited; with a fixed limit the editor would fail to allocate as soon as a
+    buffer outgrew it, and locking only what is allocated already would leave out
+    the text decrypted afterwards. When memory cannot be locked, lock_failed says
+    so, for the user to be told.
+*/
+pub fn lock_memory() {
+    LOCK_FAILED.store(!try_lock_memory(), Ordering::Relaxed);
+}
+
+// Whether lock_memory was called and could not keep decrypted text out of swap
+pub fn lock_failed() -> bool {
+    LOCK_FAILED.load(Ordering::Relaxed)
+}
+
+#[cfg(target_os = "linux")]
+fn try_lock_memory() -> bool {
+    unsafe {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) != 0
+            || limit.rlim_max != libc::RLIM_INFINITY
+        {
+            return false;
+        }
+        limit.rlim_cur = libc::RLIM_INFINITY;
+        libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) == 0
+            && libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_lock_memory() -> bool {
+    false
+}
@@ -0,0 +1,313 @@
+use crate::insertion_point::InsertionPoint;
+use crate::text_encoding::TextEncoding;
+use crossterm::event::KeyCode;
+
+pub const BYTES_PER_ROW: usize = 16;
+// "00000010  " in front of the hex bytes of every row
+const OFFSET_WIDTH: usize = 10;
+
+/*
+    The bytes of a file opened in hex view, and the nibble the cursor is on. The
+    buffer is shown as rows of an offset, sixteen hex bytes and the same bytes as
+    ASCII. Editing overwrites one nibble at a time, so the file never changes size.
+*/
+pub struct HexEditor {
+    pub bytes: Vec<u8>,
+    pub cursor: usize,
+    pub low_nibble: bool,
+    pub last_search: Option<Vec<u8>>,
+}
+
+impl HexEditor {
+    pub fn new(bytes: Vec<u8>) -> HexEditor {
+        HexEditor {
+            bytes,
+            cursor: 0,
+            low_nibble: false,
+            last_search: None,
+        }
+    }
+
+    // The text shown on the hex page
+    pub fn dump(&self) -> String {
+        if self.bytes.is_empty() {
+            return String::from("00000000  (empty file)");
+        }
+        let mut output = String::with_capacity(self.bytes.len() / BYTES_PER_ROW * 78 + 78);
+        for (n, row) in self.bytes.chunks(BYTES_PER_ROW).enumerate() {
+            if n > 0 {
+                output.push('\n');
+            }
+            output += &format!("{:08x}  ", n * BYTES_PER_ROW);
+            for column in 0..BYTES_PER_ROW {
+                if column == BYTES_PER_ROW / 2 {
+                    output.push(' ');
+                }
+                match row.get(column) {
+                    Some(b) => output += &format!("{:02x} ", b),
+                    None => output += "   ",
+                }
+            }
+            output.push('|');
+            for b in row {
+                output.push(if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                });
+            }
+            output.push('|');
+        }
+        output
+    }
+
+    // Where the cursor is drawn in the dump
+    pub fn ip(&self) -> InsertionPoint {
+        let column = self.cursor % BYTES_PER_ROW;
+        let mut ip = InsertionPoint::new();
+        ip.y = self.cursor / BYTES_PER_ROW;
+        ip.x = OFFSET_WIDTH
+            + column * 3
+            + usize::from(column >= BYTES_PER_ROW / 2)
+            + usize::from(self.low_nibble);
+        ip
+    }
+
+    pub fn move_cursor(&mut self, direction: KeyCode) {
+        if self.bytes.is_empty() {
+            return;
+        }
+        let last = self.bytes.len() - 1;
+        match direction {
+            KeyCode::Left => {
+                if self.low_nibble {
+                    self.low_nibble = false;
+                } else if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.low_nibble = true;
+                }
+            }
+            KeyCode::Right => {
+                if !self.low_nibble {
+                    self.low_nibble = true;
+                } else if self.cursor < last {
+                    self.cursor += 1;
+                    self.low_nibble = false;
+                }
+            }
+            KeyCode::Up if self.cursor >= BYTES_PER_ROW => self.cursor -= BYTES_PER_ROW,
+            KeyCode::Down if self.cursor + BYTES_PER_ROW <= last => self.cursor += BYTES_PER_ROW,
+            KeyCode::Home => {
+                self.cursor -= self.cursor % BYTES_PER_ROW;
+                self.low_nibble = false;
+            }
+            KeyCode::End => {
+                let row_end = self.cursor - self.cursor % BYTES_PER_ROW + BYTES_PER_ROW - 1;
+                self.cursor = row_end.min(last);
+                self.low_nibble = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Overwrites the nibble under the cursor and moves on, returning false for keys that are not hex digits
+    pub fn type_nibble(&mut self, c: char) -> bool {
+        let digit = match c.to_digit(16) {
+            Some(d) => d as u8,
+            None => return false,
+        };
+        let byte = match self.bytes.get_mut(self.cursor) {
+            Some(b) => b,
+            None => return false,
+        };
+        *byte = if self.low_nibble {
+            (*byte & 0xf0) | digit
+        } else {
+            (*byte & 0x0f) | (digit << 4)
+        };
+        self.move_cursor(KeyCode::Right);
+        true
+    }
+
+    // Moves the cursor to a byte offset, returning false if it is past the end of the file
+    pub fn goto(&mut self, offset: usize) -> bool {
+        if offset >= self.bytes.len() {
+            return false;
+        }
+        self.cursor = offset;
+        self.low_nibble = false;
+        true
+    }
+
+    // The first match at or after an offset, wrapping around to the start of the file
+    pub fn find_next(&self, pattern: &[u8], from: usize) -> Option<usize> {
+        let matches = self.matches(pattern);
+        matches
+            .iter()
+            .find(|&&m| m >= from)
+            .or(matches.first())
+            .copied()
+    }
+
+    // The previous match before the cursor, wrapping around to the end of the file
+    pub fn find_previous(&self, pattern: &[u8]) -> Option<usize> {
+        let matches = self.matches(pattern);
+        matches
+            .iter()
+            .rev()
+            .find(|&&m| m < self.cursor)
+            .or(matches.last())
+            .copied()
+    }
+
+    pub fn count_matches(&self, pattern: &[u8]) -> usize {
+        self.matches(pattern).len()
+    }
+
+    fn matches(&self, pattern: &[u8]) -> Vec<usize> {
+        if pattern.is_empty() || pattern.len() > self.bytes.len() {
+            return Vec::new();
+        }
+        self.bytes
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(n, _)| n)
+            .collect()
+    }
+}
+
+/*
+    Reads a byte sequence to search for, either as hex ("de ad be ef", "DEADBEEF",
+    "0x7f 0x45") or as ASCII text in double quotes ("\"ELF\""). Every group of hex
+    digits has to make whole bytes, so "a bc" is refused rather than read as "ab c".
+*/
+pub fn parse_bytes(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim();
+    if input.len() >= 2 && input.starts_with('"') && input.ends_with('"') {
+        return Some(input.as_bytes()[1..input.len() - 1].to_vec());
+    }
+    let mut bytes = Vec::new();
+    for token in input.split_whitespace() {
+        let digits = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if digits.is_empty()
+            || !digits.len().is_multiple_of(2)
+            || !digits.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return None;
+        }
+        for i in (0..digits.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&digits[i..i + 2], 16).ok()?);
+        }
+    }
+    (!bytes.is_empty()).then_some(bytes)
+}
+
+// Reads an offset in decimal, or in hex when it starts with 0x
+pub fn parse_offset(input: &str) -> Option<usize> {
+    let input = input.trim();
+    match input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => input.parse().ok(),
+    }
+}
+
+/*
+    Whether a file is better shown in hex than as text: it has NUL bytes without
+    being UTF-16, or more than one byte in ten is a control character that text
+    files do not use.
+*/
+pub fn looks_binary(data: &[u8]) -> bool {
+    if TextEncoding::detect(data).is_utf16() {
+        return false;
+    }
+    if data.contains(&0) {
+        return true;
+    }
+    let controls = data
+        .iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || b == 0x7f)
+        .count();
+    controls * 10 > data.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sequences() {
+        assert_eq!(
+            parse_bytes("de ad be ef"),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(
+            parse_bytes(" DEADBEEF "),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_bytes("0x7f 0X45"), Some(vec![0x7f, 0x45]));
+        assert_eq!(parse_bytes("\"ELF\""), Some(b"ELF".to_vec()));
+        // Odd nibbles, in the whole input or in any one group of digits
+        assert_eq!(parse_bytes("abc"), None);
+        assert_eq!(parse_bytes("a bc"), None);
+        assert_eq!(parse_bytes("ab c d"), None);
+        assert_eq!(parse_bytes("0x7 0x45"), None);
+        // Not hex at all
+        assert_eq!(parse_bytes(""), None);
+        assert_eq!(parse_bytes("0x"), None);
+        assert_eq!(parse_bytes("zz"), None);
+        assert_eq!(parse_bytes("+f"), None);
+        assert_eq!(parse_bytes("éé"), None);
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("16"), Some(16));
+        assert_eq!(parse_offset(" 0x10 "), Some(16));
+        assert_eq!(parse_offset("0XfF"), Some(255));
+        assert_eq!(parse_offset("0x"), None);
+        assert_eq!(parse_offset("0x1g"), None);
+        assert_eq!(parse_offset("-1"), None);
+        assert_eq!(parse_offset("ten"), None);
+    }
+
+    #[test]
+    fn typing_nibbles() {
+        let mut hex = HexEditor::new(vec![0x00, 0xff]);
+        assert!(hex.type_nibble('a'));
+        assert_eq!((hex.cursor, hex.low_nibble), (0, true));
+        assert!(hex.type_nibble('B'));
+        assert_eq!((hex.cursor, hex.low_nibble), (1, false));
+        assert!(!hex.type_nibble('g'));
+        assert!(hex.type_nibble('1'));
+        assert!(hex.type_nibble('2'));
+        // The last nibble of the file keeps the cursor on it
+        assert_eq!((hex.cursor, hex.low_nibble), (1, true));
+        assert!(hex.type_nibble('3'));
+        assert_eq!(hex.bytes, vec![0xab, 0x13]);
+        assert!(!HexEditor::new(Vec::new()).type_nibble('1'));
+    }
+
+    #[test]
+    fn searches_wrap_around() {
+        let mut hex = HexEditor::new(b"ab..ab..ab".to_vec());
+        let pattern = b"ab";
+        assert_eq!(hex.count_matches(pattern), 3);
+        assert_eq!(hex.find_next(pattern, 0), Some(0));
+        assert_eq!(hex.find_next(pattern, 1), Some(4));
+        assert_eq!(hex.find_next(pattern, 9), Some(0));
+        hex.cursor = 4;
+        assert_eq!(hex.find_previous(pattern), Some(0));
+        hex.cursor = 0;
+        assert_eq!(hex.find_previous(pattern), Some(8));
+        assert_eq!(hex.find_next(b"zz", 0), None);
+        assert_eq!(hex.find_previous(b"ab..ab..ab.."), None);
+        assert_eq!(hex.find_next(b"", 0), None);
+    }
+}
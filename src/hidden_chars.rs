@@ -0,0 +1,319 @@
+use std::ops::Range;
+use unicode_width::UnicodeWidthChar;
+
+/*
+    Characters that make text read differently from what it is (Trojan Source):
+    bidirectional controls that reorder what is shown, characters that take up no
+    space, and letters from other scripts that look like Latin ones. The editor
+    shows them as placeholders such as <U+202E>, and every column the cursor can
+    be at is counted with the placeholders' widths, so the helpers below take the
+    place of unicode-width wherever the buffer is laid out on screen.
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    // Changes the order characters are shown in
+    Bidi,
+    // Takes up no space
+    Invisible,
+    // Looks like this ASCII character in a word that is otherwise written in ASCII
+    LookAlike(char),
+}
+
+// A hidden or look-alike character at a display column of a line of the buffer
+pub struct Found {
+    pub line: usize,
+    pub column: usize,
+    pub c: char,
+    pub kind: Kind,
+}
+
+// Letters of other scripts that can pass for ASCII ones, and what they pass for
+const LOOK_ALIKES: [(char, char); 48] = [
+    ('а', 'a'),
+    ('е', 'e'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('ԁ', 'd'),
+    ('ԛ', 'q'),
+    ('ԝ', 'w'),
+    ('һ', 'h'),
+    ('ӏ', 'l'),
+    ('А', 'A'),
+    ('В', 'B'),
+    ('Е', 'E'),
+    ('К', 'K'),
+    ('М', 'M'),
+    ('Н', 'H'),
+    ('О', 'O'),
+    ('Р', 'P'),
+    ('С', 'C'),
+    ('Т', 'T'),
+    ('Х', 'X'),
+    ('І', 'I'),
+    ('Ј', 'J'),
+    ('Ѕ', 'S'),
+    ('У', 'Y'),
+    ('ο', 'o'),
+    ('ν', 'v'),
+    ('ρ', 'p'),
+    ('ι', 'i'),
+    ('Α', 'A'),
+    ('Β', 'B'),
+    ('Ε', 'E'),
+    ('Ζ', 'Z'),
+    ('Η', 'H'),
+    ('Ι', 'I'),
+    ('Κ', 'K'),
+    ('Μ', 'M'),
+    ('Ν', 'N'),
+    ('Ο', 'O'),
+    ('Ρ', 'P'),
+    ('Τ', 'T'),
+    ('Υ', 'Y'),
+    ('Χ', 'X'),
+];
+
+// The kind of character c is, on its own; look-alikes also depend on the word they are in
+fn kind_of(c: char) -> Option<Kind> {
+    match c {
+        '\u{202A}'..='\u{202E}'
+        | '\u{2066}'..='\u{2069}'
+        | '\u{200E}'
+        | '\u{200F}'
+        | '\u{061C}' => Some(Kind::Bidi),
+        '\u{200B}'..='\u{200D}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{FEFF}'
+        | '\u{00AD}'
+        | '\u{034F}'
+        | '\u{180E}'
+        | '\u{115F}'
+        | '\u{1160}'
+        | '\u{3164}'
+        | '\u{FFA0}'
+        | '\u{E0000}'..='\u{E007F}' => Some(Kind::Invisible),
+        '\u{FF01}'..='\u{FF5E}' => Some(Kind::LookAlike(
+            char::from_u32(c as u32 - 0xFF01 + 0x21).unwrap_or(c),
+        )),
+        _ => LOOK_ALIKES
+            .iter()
+            .find(|(look_alike, _)| *look_alike == c)
+            .map(|(_, ascii)| Kind::LookAlike(*ascii)),
+    }
+}
+
+// Joiners and direction marks are needed to write Persian, Arabic, Hebrew and emoji properly
+fn is_joiner_or_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200C}' | '\u{200D}' | '\u{200E}' | '\u{200F}' | '\u{061C}'
+    )
+}
+
+/*
+    The kind of every character of a line that is flagged, in order. A look-alike
+    is only flagged in a word that also has ASCII letters or digits in it, so text
+    written in Cyrillic or Greek is shown as it is. For the same reason joiners and
+    direction marks are only flagged right next to an ASCII letter, digit or '_',
+    where they can split or hide an identifier.
+*/
+fn flags(line: &str) -> Vec<Option<Kind>> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut flags: Vec<Option<Kind>> = chars.iter().map(|&c| kind_of(c)).collect();
+    let in_word = |c: char| c.is_alphanumeric() || c == '_' || kind_of(c).is_some();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = (start..chars.len())
+            .find(|&i| !in_word(chars[i]))
+            .unwrap_or(chars.len());
+        let mixed = chars[start..end].iter().any(|c| c.is_ascii_alphanumeric());
+        for flag in &mut flags[start..end] {
+            if matches!(flag, Some(Kind::LookAlike(_))) && !mixed {
+                *flag = None;
+            }
+        }
+        start = end + 1;
+    }
+    let ascii_ident = |i: Option<usize>| {
+        i.and_then(|i| chars.get(i))
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+    };
+    for (i, flag) in flags.iter_mut().enumerate() {
+        if is_joiner_or_mark(chars[i])
+            && !ascii_ident(i.checked_sub(1))
+            && !ascii_ident(Some(i + 1))
+        {
+            *flag = None;
+        }
+    }
+    flags
+}
+
+// What is shown in place of a flagged character
+pub fn placeholder(c: char) -> String {
+    format!("<U+{:04X}>", c as u32)
+}
+
+// The columns a character takes up on screen, given whether it is flagged
+fn char_width(c: char, flag: Option<Kind>) -> usize {
+    match flag {
+        Some(_) => placeholder(c).len(),
+        None => c.width().unwrap_or(0),
+    }
+}
+
+// How many columns a line takes up on screen
+pub fn width(line: &str) -> usize {
+    line.chars()
+        .zip(flags(line))
+        .map(|(c, flag)| char_width(c, flag))
+        .sum()
+}
+
+// How many columns the first n characters of a line take up on screen
+pub fn prefix_width(line: &str, n: usize) -> usize {
+    line.chars()
+        .zip(flags(line))
+        .take(n)
+        .map(|(c, flag)| char_width(c, flag))
+        .sum()
+}
+
+// The longest start of a line that fits in the given number of columns, and its width
+pub fn truncate(line: &str, columns: usize) -> (&str, usize) {
+    let mut width = 0;
+    for ((i, c), flag) in line.char_indices().zip(flags(line)) {
+        let w = char_width(c, flag);
+        if width + w > columns {
+            return (&line[..i], width);
+        }
+        width += w;
+    }
+    (line, width)
+}
+
+// The line as it is shown, with the display columns of the placeholders in it
+pub fn display(line: &str) -> (String, Vec<Range<usize>>) {
+    let mut shown = String::with_capacity(line.len());
+    let mut placeholders = Vec::new();
+    let mut column = 0;
+    for (c, flag) in line.chars().zip(flags(line)) {
+        let w = char_width(c, flag);
+        match flag {
+            Some(_) => {
+                shown.push_str(&placeholder(c));
+                placeholders.push(column..column + w);
+            }
+            None => shown.push(c),
+        }
+        column += w;
+    }
+    (shown, placeholders)
+}
+
+// Every flagged character in the text, in order
+pub fn find_all(text: &str) -> Vec<Found> {
+    let mut found = Vec::new();
+    for (y, line) in text.split('\n').enumerate() {
+        let mut column = 0;
+        for (c, flag) in line.chars().zip(flags(line)) {
+            if let Some(kind) = flag {
+                found.push(Found {
+                    line: y,
+                    column,
+                    c,
+                    kind,
+                });
+            }
+            column += char_width(c, flag);
+        }
+    }
+    found
+}
+
+/*
+    The text with the flagged hidden characters taken out and look-alikes replaced
+    by the ASCII they look like. Joiners and marks that are not flagged are kept.
+*/
+pub fn remove_all(text: &str) -> String {
+    let mut cleaned = String::with_capacity(text.len());
+    for (n, line) in text.split('\n').enumerate() {
+        if n > 0 {
+            cleaned.push('\n');
+        }
+        for (c, flag) in line.chars().zip(flags(line)) {
+            match flag {
+                Some(Kind::LookAlike(ascii)) => cleaned.push(ascii),
+                Some(_) => {}
+                None => cleaned.push(c),
+            }
+        }
+    }
+    cleaned
+}
+
+// A line describing a flagged character for the list of them
+pub fn describe(found: &Found) -> String {
+    let what = match found.kind {
+        Kind::Bidi => String::from("changes the direction text is shown in"),
+        Kind::Invisible => String::from("invisible"),
+        Kind::LookAlike(ascii) => format!("looks like '{}'", ascii),
+    };
+    format!("line {}: {} {}", found.line + 1, placeholder(found.c), what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_counted_in_widths() {
+        // "<U+202E>" takes 8 columns, and the CJK character 2
+        let line = "ab\u{202E}c日";
+        assert_eq!(width(line), 2 + 8 + 1 + 2);
+        assert_eq!(prefix_width(line, 3), 10);
+        assert_eq!(truncate(line, 9), ("ab", 2));
+        assert_eq!(truncate(line, 12), ("ab\u{202E}c", 11));
+        assert_eq!(truncate(line, 40), (line, 13));
+        let (shown, placeholders) = display(line);
+        assert_eq!(shown, "ab<U+202E>c日");
+        assert_eq!(placeholders.len(), 1);
+        assert_eq!(placeholders[0], 2..10);
+    }
+
+    #[test]
+    fn look_alikes_are_flagged_in_ascii_words_only() {
+        // A Cyrillic 'а' in "pаss" is flagged, in the Russian word "пароль" nothing is
+        let found = find_all("let p\u{0430}ss = 1;\nпароль");
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].line, found[0].column), (0, 5));
+        assert!(found[0].kind == Kind::LookAlike('a'));
+    }
+
+    #[test]
+    fn joiners_are_flagged_next_to_identifiers_only() {
+        // Inside an identifier
+        assert_eq!(find_all("is\u{200D}Admin").len(), 1);
+        assert_eq!(find_all("x\u{200F} = 1").len(), 1);
+        // Persian with a zero-width non-joiner, Hebrew with a mark and a family emoji
+        assert!(find_all("می\u{200C}خواهم").is_empty());
+        assert!(find_all("שלום\u{200F} ").is_empty());
+        assert!(find_all("👨\u{200D}👩\u{200D}👧").is_empty());
+        // Other invisible characters are flagged wherever they are
+        assert_eq!(find_all("\u{200B} ").len(), 1);
+    }
+
+    #[test]
+    fn only_flagged_characters_are_removed() {
+        assert_eq!(
+            remove_all("is\u{200D}Admin = p\u{0430}ss\u{202E};\nمی\u{200C}خواهم"),
+            "isAdmin = pass;\nمی\u{200C}خواهم"
+        );
+    }
+}
@@ -1,21 +1,21 @@
+use crate::hidden_chars;
 use crate::insertion_point::*;
 use crate::page::*;
 use crossterm::event::KeyCode;
 use std::cmp;
-use unicode_truncate::UnicodeTruncateStr;
+use std::ops::Range;
 
 /*
     Struct responsible for moving the user's (i)nsertion (p)oint while
-    the program is running.
+    the program is running. The anchor is where a selection was started;
+    the text between it and the insertion point is selected.
 */
 // ip.x, ip.y indicates the index of cursor and use the screen_cols and rows to store the screen size
 pub struct KeyHandler {
     pub ip: InsertionPoint,
+    pub anchor: Option<InsertionPoint>,
     pub screen_cols: usize,
     pub screen_rows: usize,
-    pub bytes_in_row: Vec<usize>,
-    pub width_in_row: Vec<usize>,
-    pub num_of_rows: usize,
     pub row_offset: usize,
     pub column_offset: usize,
 }
@@ -24,11 +24,9 @@ impl KeyHandler {
     pub fn new(window_size: (usize, usize)) -> KeyHandler {
         KeyHandler {
             ip: InsertionPoint::new(),
+            anchor: None,
             screen_cols: window_size.0,
             screen_rows: window_size.1 - 2,
-            bytes_in_row: Vec::new(),
-            width_in_row: Vec::new(),
-            num_of_rows: 0,
             row_offset: 0,
             column_offset: 0,
         }
@@ -48,211 +46,221 @@ impl KeyHandler {
 
     //move the insertion point based on user's keypress
     pub fn move_ip(&mut self, operation: KeyCode, on_screen: &Page) {
+        let buffer = &on_screen.contents;
         match operation {
-            KeyCode::Up => {
-                if self.ip.y > 0 {
-                    self.ip.y -= 1;
-                    self.ip.x = cmp::min(self.ip.x, *self.width_in_row.get(self.ip.y).unwrap());
-                    self.ip.x = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x)
-                        .1;
-                }
+            KeyCode::Up if self.ip.y > 0 => {
+                self.ip.y -= 1;
+                self.ip.x = hidden_chars::truncate(&buffer.line(self.ip.y), self.ip.x).1;
             }
-            KeyCode::Down => {
-                if self.ip.y < self.num_of_rows - 1 {
-                    self.ip.y += 1;
-                    self.ip.x = cmp::min(self.ip.x, *self.width_in_row.get(self.ip.y).unwrap());
-                    self.ip.x = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x)
-                        .1;
-                }
+            KeyCode::Down if buffer.has_line(self.ip.y + 1) => {
+                self.ip.y += 1;
+                self.ip.x = hidden_chars::truncate(&buffer.line(self.ip.y), self.ip.x).1;
             }
             KeyCode::Left => {
                 if self.ip.x > 0 {
-                    self.ip.x -= 1;
-                    let (_, mut w) = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x);
-                    while w != self.ip.x {
-                        self.ip.x -= 1;
-                        w = on_screen
-                            .row_contents
-                            .get(self.ip.y)
-                            .unwrap()
-                            .unicode_truncate(self.ip.x)
-                            .1;
-                    }
+                    // Wide characters take up more than one column
+                    self.ip.x = hidden_chars::truncate(&buffer.line(self.ip.y), self.ip.x - 1).1;
                 } else if self.ip.y > 0 {
                     self.ip.y -= 1;
-                    self.ip.x = *self.width_in_row.get(self.ip.y).unwrap();
+                    self.ip.x = buffer.line_width(self.ip.y);
                 }
             }
             KeyCode::Right => {
-                if self.ip.x < *self.width_in_row.get(self.ip.y).unwrap() {
-                    self.ip.x += 1;
-                    let (_, mut w) = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x);
-                    while w != self.ip.x {
-                        self.ip.x += 1;
-                        w = on_screen
-                            .row_contents
-                            .get(self.ip.y)
-                            .unwrap()
-                            .unicode_truncate(self.ip.x)
-                            .1;
-                    }
-                } else if self.ip.y < self.num_of_rows - 1 {
+                if self.ip.x < buffer.line_width(self.ip.y) {
+                    let index = self.get_current_char_index(on_screen);
+                    self.set_ip(buffer.position(index + 1));
+                } else if buffer.has_line(self.ip.y + 1) {
                     self.ip.x = 0;
                     self.ip.y += 1;
                 }
                 // else is for default, the limit is set to be the screen size for further adjustment
             }
-            KeyCode::End => self.ip.x = *self.width_in_row.get(self.ip.y).unwrap(),
+            KeyCode::End => self.ip.x = buffer.line_width(self.ip.y),
             KeyCode::Home => self.ip.x = 0,
             _ => {} //more code needed
         }
     }
 
+    // Moves the insertion point and extends the selection, starting one where the cursor was if there is none
+    pub fn select(&mut self, operation: KeyCode, on_screen: &Page) {
+        if self.anchor.is_none() {
+            self.anchor = Some(self.ip.clone());
+        }
+        self.move_ip(operation, on_screen);
+    }
+
+    // The chars between the anchor and the insertion point, if any are selected
+    pub fn selection(&self, on_screen: &Page) -> Option<Range<usize>> {
+        let anchor = self.anchor.as_ref()?;
+        let anchor = on_screen.contents.char_index(anchor.x, anchor.y);
+        let index = self.get_current_char_index(on_screen);
+        match anchor.cmp(&index) {
+            cmp::Ordering::Less => Some(anchor..index),
+            cmp::Ordering::Greater => Some(index..anchor),
+            cmp::Ordering::Equal => None,
+        }
+    }
+
+    /*
+        Edits the text at the insertion point. When text is selected, Backspace and
+        Delete remove it, typing replaces it, and Tab and Shift+Tab indent or outdent
+        the lines it covers.
+    */
     pub fn insertion(&mut self, operation: KeyCode, on_screen: &mut Page) {
-        match operation {
-            KeyCode::Char(c) => {
-                on_screen
-                    .contents
-                    .insert(self.get_current_location_in_string(on_screen), c);
-                on_screen.row_contents = split_with_n(&on_screen.contents);
-                self.ip.x += 1;
-                let (_, mut w) = on_screen
-                    .row_contents
-                    .get(self.ip.y)
-                    .unwrap()
-                    .unicode_truncate(self.ip.x);
-                while w != self.ip.x {
-                    self.ip.x += 1;
-                    w = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x)
-                        .1;
+        let mut index = self.get_current_char_index(on_screen);
+        if let Some(range) = self.selection(on_screen) {
+            match operation {
+                KeyCode::Tab | KeyCode::BackTab => {
+                    self.indent(operation == KeyCode::Tab, on_screen);
+                    return;
+                }
+                KeyCode::Char(_) | KeyCode::Enter | KeyCode::Backspace | KeyCode::Delete => {
+                    on_screen.contents.remove(range.clone());
+                    index = range.start;
+                    self.anchor = None;
+                    if matches!(operation, KeyCode::Backspace | KeyCode::Delete) {
+                        self.set_ip(on_screen.contents.position(index));
+                        return;
+                    }
                 }
+                _ => return,
+            }
+        }
+        self.anchor = None;
+        let buffer = &mut on_screen.contents;
+        let new_index = match operation {
+            KeyCode::Char(c) => {
+                buffer.insert(index, c.encode_utf8(&mut [0; 4]));
+                index + 1
             }
-
             KeyCode::Tab => {
-                on_screen
-                    .contents
-                    .insert_str(self.get_current_location_in_string(on_screen), "    ");
-                self.bytes_in_row[self.ip.y] += 4;
-                self.ip.x += 4;
+                buffer.insert(index, "    ");
+                index + 4
+            }
+            KeyCode::BackTab => {
+                self.indent(false, on_screen);
+                return;
             }
             KeyCode::Backspace => {
-                if self.ip.x == 0 {
-                    if self.ip.y == 0 {
-                        //do nothing since insertion point is at origin (top-left)
-                    } else {
-                        on_screen
-                            .contents
-                            .remove(self.get_current_location_in_string(on_screen) - 1);
-                        self.ip.x = self.width_in_row[self.ip.y - 1];
-                        self.ip.y -= 1;
-                    }
-                } else {
-                    let a = on_screen.contents[..self.get_current_location_in_string(on_screen)]
-                        .to_string()
-                        .pop()
-                        .unwrap()
-                        .len_utf8();
-                    on_screen
-                        .contents
-                        .remove(self.get_current_location_in_string(on_screen) - a);
-                    self.ip.x -= 1;
-                    let (_, mut w) = on_screen
-                        .row_contents
-                        .get(self.ip.y)
-                        .unwrap()
-                        .unicode_truncate(self.ip.x);
-                    while w != self.ip.x {
-                        self.ip.x -= 1;
-                        w = on_screen
-                            .row_contents
-                            .get(self.ip.y)
-                            .unwrap()
-                            .unicode_truncate(self.ip.x)
-                            .1;
-                    }
+                if index == 0 {
+                    //do nothing since insertion point is at origin (top-left)
+                    return;
                 }
+                buffer.remove(index - 1..index);
+                index - 1
             }
-
             KeyCode::Delete => {
-                if self.ip.x == self.bytes_in_row[self.ip.y] {
-                    if self.ip.y == self.num_of_rows - 1 {
-                        //do nothing since insertion point is at end of file (bottom-right)
-                    } else {
-                        on_screen
-                            .contents
-                            .remove(self.get_current_location_in_string(on_screen));
-                        self.bytes_in_row[self.ip.y] += self.bytes_in_row[self.ip.y + 1] - 1;
-                        self.bytes_in_row.remove(self.ip.y + 1);
-                        self.num_of_rows -= 1;
-                    }
-                } else {
-                    on_screen
-                        .contents
-                        .remove(self.get_current_location_in_string(on_screen));
-                    self.bytes_in_row[self.ip.y] -= 1;
+                if buffer.is_end(index) {
+                    //do nothing since insertion point is at end of file (bottom-right)
+                    return;
                 }
+                buffer.remove(index..index + 1);
+                index
             }
-
             KeyCode::Enter => {
-                on_screen
-                    .contents
-                    .insert(self.get_current_location_in_string(on_screen), '\n');
-                self.ip.x = 0;
-                self.ip.y += 1;
+                buffer.insert(index, "\n");
+                index + 1
             }
-            _ => {}
-        }
+            _ => return,
+        };
+        self.set_ip(buffer.position(new_index));
     }
 
-    //Backspace and moving forward when typing
-    pub fn get_current_location_in_string(&mut self, on_screen: &Page) -> usize {
-        let mut x = 0;
-        for i in 0..self.ip.y {
-            x += self.bytes_in_row[i];
+    /*
+        Adds four spaces to the start of each line the selection covers, or of the
+        cursor's line when nothing is selected, or takes up to four spaces (or a tab)
+        away when outdenting. A selection ending at the start of a line leaves that
+        line alone. The anchor and cursor move along with their text.
+    */
+    fn indent(&mut self, indenting: bool, on_screen: &mut Page) {
+        let first = cmp::min(self.ip.y, self.anchor.as_ref().map_or(self.ip.y, |a| a.y));
+        let mut last = cmp::max(self.ip.y, self.anchor.as_ref().map_or(self.ip.y, |a| a.y));
+        if last > first {
+            let end = if self.ip.y == last {
+                &self.ip
+            } else {
+                self.anchor.as_ref().unwrap()
+            };
+            if end.x == 0 {
+                last -= 1;
+            }
         }
-        let (s, _) = match on_screen.row_contents.get(self.ip.y) {
-            Some(s) => s,
-            None => "", // is this right?
+        let buffer = &mut on_screen.contents;
+        let mut changes = Vec::new();
+        for y in first..=last {
+            let start = buffer.char_index(0, y);
+            let line = buffer.line(y);
+            let width = if indenting {
+                buffer.insert(start, "    ");
+                4
+            } else if line.starts_with('\t') {
+                buffer.remove(start..start + 1);
+                1
+            } else {
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                buffer.remove(start..start + cmp::min(spaces, 4));
+                cmp::min(spaces, 4)
+            };
+            changes.push(width);
+        }
+        // Text at the very start of a line stays there, so whole-line selections stay whole lines
+        let shift = |point: &mut InsertionPoint| {
+            if point.y >= first && point.y <= last && point.x > 0 {
+                let width = changes[point.y - first];
+                point.x = if indenting {
+                    point.x + width
+                } else {
+                    point.x.saturating_sub(width)
+                };
+            }
+        };
+        shift(&mut self.ip);
+        if let Some(anchor) = &mut self.anchor {
+            shift(anchor);
         }
-        .unicode_truncate(self.ip.x);
-        x += s.replace('\n', "").len();
-        x
     }
-}
 
-pub fn split_with_n(content: &String) -> Vec<String> {
-    let mut result = Vec::new();
-    let mut last = 0;
-    for (index, _) in content.match_indices('\n') {
-        result.push(content[last..index + 1].to_string());
-        last = index + 1;
+    // Turns the selected text into upper or lower case, keeping it selected
+    pub fn change_case(&mut self, upper: bool, on_screen: &mut Page) -> bool {
+        let range = match self.selection(on_screen) {
+            Some(r) => r,
+            None => return false,
+        };
+        let buffer = &mut on_screen.contents;
+        let text = buffer.remove(range.clone());
+        let text = if upper {
+            text.to_uppercase()
+        } else {
+            text.to_lowercase()
+        };
+        buffer.insert(range.start, &text);
+        // Some characters change length, such as 'ß' becoming "SS"
+        let end = range.start + text.chars().count();
+        let (x, y) = buffer.position(range.start);
+        self.anchor = Some(InsertionPoint { x, y });
+        self.set_ip(buffer.position(end));
+        true
     }
-    if last < content.len() {
-        result.push(content[last..].to_string());
+
+    // Puts text in at the insertion point in place of whatever is selected, leaving the cursor after it
+    pub fn insert_text(&mut self, text: &str, on_screen: &mut Page) {
+        let mut index = self.get_current_char_index(on_screen);
+        if let Some(range) = self.selection(on_screen) {
+            on_screen.contents.remove(range.clone());
+            index = range.start;
+        }
+        self.anchor = None;
+        on_screen.contents.insert(index, text);
+        self.set_ip(on_screen.contents.position(index + text.chars().count()));
     }
-    if result.len() == 0 {
-        return vec![String::from("")];
-    } else {
-        return result;
+
+    //the char index in the page's buffer that the insertion point is at
+    pub fn get_current_char_index(&self, on_screen: &Page) -> usize {
+        on_screen.contents.char_index(self.ip.x, self.ip.y)
+    }
+
+    fn set_ip(&mut self, (x, y): (usize, usize)) {
+        self.ip.x = x;
+        self.ip.y = y;
     }
 }
@@ -0,0 +1,110 @@
+/*
+    The line ending style of a file. Buffers always hold plain '\n' line breaks so the
+    rest of the editor only has to deal with one kind; the style found when the file
+    is loaded is put back when it is saved.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    // The most common line ending in the text, LF for text without any
+    pub fn detect(text: &str) -> LineEnding {
+        let crlf = text.matches("\r\n").count();
+        let cr = text.matches('\r').count() - crlf;
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > 0 && crlf >= lf && crlf >= cr {
+            LineEnding::Crlf
+        } else if cr > lf {
+            LineEnding::Cr
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    // Turns this style's line endings into '\n'; anything else is left as it is
+    pub fn normalize(&self, text: String) -> String {
+        match self {
+            LineEnding::Lf => text,
+            LineEnding::Crlf => text.replace("\r\n", "\n"),
+            // CRLFs first, or each of them would become two line breaks
+            LineEnding::Cr => text.replace("\r\n", "\n").replace('\r', "\n"),
+        }
+    }
+
+    /*
+        Whether the text has line breaks of another kind that normalize turns into '\n',
+        so that they are written back in this style when the file is saved.
+    */
+    pub fn converts_others(&self, text: &str) -> bool {
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        match self {
+            LineEnding::Lf => false,
+            LineEnding::Crlf => lf > 0,
+            LineEnding::Cr => lf + crlf > 0,
+        }
+    }
+
+    // Turns the buffer's '\n' line breaks into this style for writing to disk
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            LineEnding::Lf => text.to_owned(),
+            LineEnding::Crlf => text.replace('\n', "\r\n"),
+            LineEnding::Cr => text.replace('\n', "\r"),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_most_common_ending() {
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\rb\rc\n"), LineEnding::Cr);
+        assert_eq!(LineEnding::detect("a\nb\r\nc\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("no line breaks"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn round_trips() {
+        for (ending, text) in [
+            (LineEnding::Lf, "a\nb\n"),
+            (LineEnding::Crlf, "a\r\nb\r\n"),
+            (LineEnding::Cr, "a\rb\r"),
+        ] {
+            let normalized = ending.normalize(text.to_owned());
+            assert_eq!(normalized, "a\nb\n");
+            assert_eq!(ending.apply(&normalized), text);
+            assert!(!ending.converts_others(text));
+        }
+    }
+
+    #[test]
+    fn mixed_crlf_is_not_doubled() {
+        let text = "a\rb\r\nc\r";
+        assert_eq!(LineEnding::Cr.normalize(text.to_owned()), "a\nb\nc\n");
+        assert!(LineEnding::Cr.converts_others(text));
+    }
+
+    #[test]
+    fn other_endings_are_reported() {
+        let text = "a\r\nb\nc\rd";
+        assert_eq!(LineEnding::Crlf.normalize(text.to_owned()), "a\nb\nc\rd");
+        assert!(LineEnding::Crlf.converts_others(text));
+        assert!(!LineEnding::Lf.converts_others(text));
+    }
+}
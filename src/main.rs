@@ -1,37 +1,84 @@
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::style::*;
 use crossterm::{event, terminal};
 
-use unicode_truncate::UnicodeTruncateStr;
-use unicode_width::UnicodeWidthStr;
-
+pub mod clipboard;
+pub mod editor_error;
+pub mod encryption;
 pub mod file_io;
+pub mod file_lock;
+pub mod gpg;
+pub mod hardening;
+pub mod hex_editor;
+pub mod hidden_chars;
 pub mod insertion_point;
 pub mod key_handler;
 pub mod language;
+pub mod line_ending;
+pub mod masking;
+pub mod merge;
 pub mod page;
+pub mod recovery;
 pub mod screen;
-
-use file_io::FileIO;
+pub mod secret_scan;
+pub mod signature;
+pub mod text_buffer;
+pub mod text_encoding;
+pub mod undo;
+pub mod vault;
+
+use editor_error::EditorError;
+use encryption::EncryptionKey;
+use file_io::{FileContents, FileIO};
+use file_lock::{FileLock, LockStatus};
+use gpg::GpgFile;
+use hardening::ZeroizingAllocator;
+use insertion_point::InsertionPoint;
+use line_ending::LineEnding;
 use page::*;
 use screen::*;
+use text_encoding::TextEncoding;
 
 //use device_query::{DeviceQuery, DeviceState, Keycode};
 
+// Every allocation is wiped when it is freed, so no plaintext is left behind in freed memory
+#[global_allocator]
+static ALLOCATOR: ZeroizingAllocator = ZeroizingAllocator;
+
 // Configurations
-static AUTOSAVE: bool = false;
 static AUTOSAVEEVERYNOPERATIONS: usize = 1000;
+// How long the keyboard has to be quiet before the file is checked for outside changes
+static DISK_CHECK_AFTER_IDLE_SECONDS: u64 = 2;
+// How often timers such as the clipboard's are checked while waiting for a key
+static TICK_MILLISECONDS: u64 = 250;
 
 fn main() {
 
     let _hl_instance = (HighLight::Normal, HighLight::Number, HighLight::Search);
 
     // SETUP
+    hardening::harden_process();
     //introduce Tidy_Up instance so that raw mode is disabled at end of main
     let _tidy_up = TidyUp;
-    let opened_file_path = FileIO::get_file_path(std::env::args());
+    // A panic hands the terminal back before its message is printed, so the message stays readable
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = terminal::disable_raw_mode();
+        let _ = Screen::leave_terminal();
+        default_hook(info);
+    }));
+    // Problems opening the file are shown once the editor is running
+    let mut startup_error: Option<EditorError> = None;
+    let mut opened_file_path = match FileIO::get_file_path(std::env::args()) {
+        Ok(p) => p,
+        Err(e) => {
+            startup_error = Some(e);
+            None
+        }
+    };
     let mut extension: String = String::from("");
     let args: Vec<String> = std::env::args().collect(); //get command-line args
     let mut passed_arg: String = String::new();
@@ -49,26 +96,116 @@ fn main() {
 
     let mut save_as_warned = false;
 
+    // Encrypted files are only read once the passphrase has been entered
+    let encrypted = match &opened_file_path {
+        Some(path) => FileIO::is_encrypted_file(path),
+        None => false,
+    };
+    // OpenPGP files are decrypted by gpg, which asks for any passphrase itself
+    let mut gpg_file: Option<GpgFile> = None;
+    let contents = if encrypted {
+        // Keeps the passphrase and the decrypted text out of swap
+        hardening::lock_memory();
+        FileContents::Text(String::new(), TextEncoding::default())
+    } else if let Some(path) = opened_file_path.clone().filter(FileIO::is_gpg_file) {
+        hardening::lock_memory();
+        match FileIO::read_gpg_file(&path) {
+            Ok((c, file)) => {
+                gpg_file = Some(file);
+                c
+            }
+            Err(e) => {
+                startup_error = Some(e);
+                opened_file_path = None;
+                FileContents::Text(String::new(), TextEncoding::default())
+            }
+        }
+    } else {
+        match FileIO::get_file_contents(&opened_file_path) {
+            Ok(c) => c,
+            Err(e) => {
+                startup_error = Some(e);
+                opened_file_path = None;
+                FileContents::Text(String::new(), TextEncoding::default())
+            }
+        }
+    };
+    if startup_error.is_some() {
+        // Saving has to ask for a name rather than overwrite a file that could not be read
+        passed_arg = String::new();
+    } else if opened_file_path.is_none() && get_gpg_extension(&passed_arg).is_some() {
+        // A new .gpg file is encrypted to the user's own key when it is first saved
+        gpg_file = Some(GpgFile::new_file(&passed_arg));
+    }
+    // Another editor with the file open makes this one read only
+    let mut lock_status: Option<LockStatus> = None;
+    if let Some(path) = &opened_file_path {
+        match FileLock::acquire(path) {
+            Ok(status) => lock_status = Some(status),
+            Err(e) => startup_error = Some(e),
+        }
+    }
+
     // Setup
     match crossterm::terminal::enable_raw_mode() {
         Ok(_a) => {}
         Err(e) => eprint!("{}", e),
     };
+    if let Err(e) = Screen::enter_terminal() {
+        eprint!("{}", e);
+    }
     //Creates the screen on which everything is displayed
     let mut screen: Screen = Screen::new(opened_file_path.clone(), extension);
+    screen.gpg = gpg_file;
     // Counts the number of operations that have been executed since the last autosave or file opening
     let mut operations: usize = 0;
+    // Autosave is on unless SFE_AUTOSAVE is 0
+    let autosave = std::env::var("SFE_AUTOSAVE").map_or(true, |v| v.trim() != "0");
     // Creates a stack of screens
     // Creates the screen for interacting with the file
-    screen.push(Page::new_with_contents(
-        PageType::Text,
-        FileIO::get_file_contents(&opened_file_path),
-    ));
+    screen.push(Page::new(PageType::Text));
+    screen.load_contents(contents);
+    if let Some(status) = lock_status {
+        screen.set_lock(status);
+    }
+    // Signatures cover the file as it is on disk, so they are checked before any passphrase
+    if let Some(path) = &opened_file_path {
+        if let Err(e) = screen
+            .check_signature(path)
+            .and_then(|_| screen.resume_signing())
+        {
+            startup_error.get_or_insert(e);
+        }
+    }
     screen.reset_prompt();
+    if let Some(e) = &startup_error {
+        screen.show_error(e);
+    }
+    if encrypted {
+        screen.add(PageType::Passphrase);
+        screen
+            .active_mut()
+            .set_prompt(String::from("File is encrypted\nPassphrase:"));
+    } else {
+        if let Some(path) = &opened_file_path {
+            screen.record_disk_state(path);
+            screen.restore_history();
+        }
+        screen.add_recovery_page();
+        screen.add_vault_page();
+        if screen.needs_lock_passphrase() {
+            screen.pending_passphrase = None;
+            screen.add(PageType::SetLockPassphrase);
+            screen.active_mut().set_prompt(String::from(
+                "gpg files are locked when idle; choose a passphrase to unlock this one with\nNew lock passphrase:",
+            ));
+        }
+    }
 
     let mut indices: Vec<usize>; // = Vec::new(); //list of indices where find text occurs
     let mut coordinates: Vec<(usize, usize)> = Vec::new(); //list of x,y pairs for the cursor after find
     let mut point = 0; //used to traverse found instances
+    let mut last_key_time = Instant::now();
 
     // render the context
     // PROGRAM RUNNING
@@ -79,10 +216,64 @@ fn main() {
             Err(e) => eprint!("{}", e),
         };
 
+        // Nothing is drawn again while waiting for a key unless a timer went off
+        let mut ticked = false;
+        while !event::poll(Duration::from_millis(TICK_MILLISECONDS)).unwrap_or(true) {
+            if screen.tick(last_key_time.elapsed()) {
+                ticked = true;
+                break;
+            }
+        }
+        if ticked {
+            continue;
+        }
+
         // Watches for key commands
         if let Event::Key(event) =
             event::read().unwrap_or(Event::Key(KeyEvent::new(KeyCode::Null, KeyModifiers::NONE)))
         {
+            /*
+                crossterm 0.23 does not report focus changes, so the first key after a pause
+                stands in for the user coming back to the editor. If the file was changed in
+                the meantime the key is dropped and the user is asked what to do first.
+            */
+            let idle =
+                last_key_time.elapsed() >= Duration::from_secs(DISK_CHECK_AFTER_IDLE_SECONDS);
+            last_key_time = Instant::now();
+            // Nothing but typing the passphrase gets past the lock screen, short of quitting
+            if screen.locked() {
+                match event {
+                    // Quits without saving, letting go of the text unseen
+                    KeyEvent {
+                        code: KeyCode::Char('w'),
+                        modifiers: event::KeyModifiers::CONTROL,
+                    } => {
+                        screen.discard();
+                        break;
+                    }
+                    KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: event::KeyModifiers::NONE,
+                    } => screen.unlock(),
+                    KeyEvent {
+                        code: input @ (KeyCode::Char(..) | KeyCode::Backspace | KeyCode::Delete),
+                        modifiers: event::KeyModifiers::NONE | event::KeyModifiers::SHIFT,
+                    } => screen.insertion(input),
+                    _ => {}
+                }
+                continue;
+            }
+            if idle && screen.page_stack.len() == 1 && screen.changed_on_disk(false) {
+                if screen.read_only() {
+                    // Nothing to lose in a read only buffer, so it just follows the file
+                    if let Err(e) = screen.reload_from_disk() {
+                        screen.show_error(&e);
+                    }
+                } else {
+                    screen.add_conflict_page();
+                    continue;
+                }
+            }
             match event {
                 //exit program
                 KeyEvent {
@@ -94,6 +285,20 @@ fn main() {
                     code: KeyCode::Char('s'),
                     modifiers: event::KeyModifiers::CONTROL,
                 } => {
+                    if screen.active().display_type == PageType::Autosave {
+                        screen.add(PageType::SaveAs);
+                        screen
+                            .active_mut()
+                            .set_prompt(String::from("Save autosave as:"));
+                        continue;
+                    }
+                    if screen.awaiting_passphrase()
+                        || screen.active().display_type == PageType::Conflict
+                        || screen.active().display_type == PageType::Secrets
+                        || screen.refuse_if_read_only()
+                    {
+                        continue;
+                    }
                     let pathname: String = String::from(match &opened_file_path {
                         Some(t) => t.as_str(),
                         None => "",
@@ -103,26 +308,35 @@ fn main() {
                         trigger_saveas(&mut screen);
                     } else if !Path::new(pathname.as_str()).exists() {
                         //cmd-line arg refers to new file
-                        screen.file_name = Some(passed_arg.clone());
-                        screen.modified = false;
-                        screen.reset_prompt();
-                        let file_text: &String = &screen.active().contents;
-                        match FileIO::overwrite_to_file(&passed_arg, file_text) {
-                            Ok(_) => {}
-                            Err(e) => eprint!("Failed to save because of error {}", e),
+                        match screen.save(&passed_arg) {
+                            Ok(true) => {
+                                screen.set_file_name(&passed_arg);
+                                screen.modified = false;
+                                screen.reset_prompt();
+                            }
+                            // Waiting for the user to decide about the secrets in the buffer
+                            Ok(false) => {}
+                            Err(e) => screen.show_error(&e),
                         }
                     } else {
                         //else save as usual
                         // screen.active_mut().set_prompt(String::from("Saved!"));
-                        let new_text: &String = &screen.active().contents;
-                        match FileIO::overwrite_to_file(&pathname, new_text) {
-                            Ok(_) => {}
-                            Err(e) => eprint!("Failed to save because of error {}", e),
-                        };
-                        screen.modified = false;
-                        if screen.find_mode() {
-                            screen.active_mut().set_prompt(String::from(""));
+                        if screen.changed_on_disk(true) {
+                            // Saving now would silently throw away the other program's changes
+                            screen.add_conflict_page();
+                            continue;
                         }
+                        match screen.save(&pathname) {
+                            Ok(true) => {
+                                screen.modified = false;
+                                if screen.find_mode() {
+                                    screen.active_mut().set_prompt(String::from(""));
+                                }
+                            }
+                            Ok(false) => {}
+                            // The buffer stays modified so the user can retry or use Save As
+                            Err(e) => screen.show_error(&e),
+                        };
                         screen.mode = Mode::Normal;
                         // break
                     }
@@ -153,6 +367,11 @@ fn main() {
                         point += 1;
                         screen.key_handler.ip.x = coordinates[point].0;
                         screen.key_handler.ip.y = coordinates[point].1;
+                    } else if screen.page_stack.len() == 1 {
+                        let last_search = screen.hex.as_ref().and_then(|h| h.last_search.clone());
+                        if let Some(pattern) = last_search {
+                            screen.hex_find(pattern, true, true);
+                        }
                     }
                 }
 
@@ -165,6 +384,11 @@ fn main() {
                         point -= 1;
                         screen.key_handler.ip.x = coordinates[point].0;
                         screen.key_handler.ip.y = coordinates[point].1;
+                    } else if screen.page_stack.len() == 1 {
+                        let last_search = screen.hex.as_ref().and_then(|h| h.last_search.clone());
+                        if let Some(pattern) = last_search {
+                            screen.hex_find(pattern, false, true);
+                        }
                     }
                 }
 
@@ -183,11 +407,37 @@ fn main() {
                             Some(t) => t.as_str(),
                             None => "",
                         });
-                        screen.add_info_page(String::from(FileIO::get_metadata(&pathname)));
+                        match FileIO::get_metadata(&pathname) {
+                            Ok(info) => screen.add_info_page(info),
+                            Err(e) => screen.show_error(&e),
+                        }
                     }
                 
                 }
 
+                // Clipboard
+                KeyEvent {
+                    code: KeyCode::Char('k'),
+                    modifiers: event::KeyModifiers::CONTROL,
+                } if screen.page_stack.len() == 1
+                    && screen.active().display_type == PageType::Text =>
+                {
+                    screen.copy();
+                }
+                KeyEvent {
+                    code: KeyCode::Char('x'),
+                    modifiers: event::KeyModifiers::CONTROL,
+                } if screen.page_stack.len() == 1 => screen.cut(),
+                // Pasting into a prompt could put a copied secret where it is shown or kept
+                KeyEvent {
+                    code: KeyCode::Char('v'),
+                    modifiers: event::KeyModifiers::CONTROL,
+                } if screen.page_stack.len() == 1
+                    && screen.active().display_type == PageType::Text =>
+                {
+                    screen.paste();
+                }
+
                 KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: event::KeyModifiers::CONTROL,
@@ -207,19 +457,66 @@ fn main() {
                         | KeyCode::End),
                     modifiers: event::KeyModifiers::NONE,
                 } => {
-                    if screen.active().display_type != PageType::Info {
+                    if screen.active().display_type == PageType::Hex {
+                        if let Some(hex) = &mut screen.hex {
+                            hex.move_cursor(direction);
+                        }
+                        screen.refresh_hex();
+                    } else if screen.active().display_type != PageType::Info {
                         screen.move_ip(direction);
                     }
                 },
 
+                // Events that select text
+                KeyEvent {
+                    code:
+                        direction @ (KeyCode::Up
+                        | KeyCode::Down
+                        | KeyCode::Left
+                        | KeyCode::Right
+                        | KeyCode::Home
+                        | KeyCode::End),
+                    modifiers: event::KeyModifiers::SHIFT,
+                } if screen.page_stack.len() == 1
+                    && screen.active().display_type == PageType::Text =>
+                {
+                    screen.select(direction);
+                }
+
                 // Events that change the text
                 KeyEvent {
                     code:
                         input
-                        @ (KeyCode::Char(..) | KeyCode::Tab | KeyCode::Backspace | KeyCode::Delete),
+                        @ (KeyCode::Char(..)
+                        | KeyCode::Tab
+                        | KeyCode::BackTab
+                        | KeyCode::Backspace
+                        | KeyCode::Delete),
                     modifiers: event::KeyModifiers::NONE | event::KeyModifiers::SHIFT,
                 } => {
-                    if screen.active().display_type != PageType::Info {
+                    if screen.active().display_type == PageType::Recovery {
+                        recovery_choice(&mut screen, input);
+                    } else if screen.active().display_type == PageType::Conflict {
+                        conflict_choice(&mut screen, input);
+                    } else if screen.active().display_type == PageType::Secrets {
+                        secrets_choice(&mut screen, input);
+                    } else if screen.active().display_type == PageType::HiddenChars {
+                        hidden_chars_choice(&mut screen, input);
+                    } else if screen.active().display_type == PageType::Hex {
+                        if let KeyCode::Char(c) = input {
+                            if !screen.refuse_if_read_only()
+                                && screen.hex.as_mut().is_some_and(|hex| hex.type_nibble(c))
+                            {
+                                screen.modified = true;
+                                screen.refresh_hex();
+                            }
+                        }
+                    } else if screen.active().display_type == PageType::Text {
+                        if !screen.refuse_if_read_only() {
+                            screen.modified = true;
+                            screen.insertion(input);
+                        }
+                    } else if screen.active().display_type != PageType::Info {
                         screen.modified = true;
                         screen.insertion(input);
                     }
@@ -235,22 +532,33 @@ fn main() {
                                 screen.mode = Mode::Normal;
                                 continue;
                             }
-                            screen.insertion(KeyCode::Enter);
+                            if !screen.refuse_if_read_only() {
+                                screen.insertion(KeyCode::Enter);
+                            }
                             continue;
                         }
+                        PageType::Autosave => screen.insertion(KeyCode::Enter),
+                        // The autosave opened next to the file is saved as a file of its own
+                        PageType::SaveAs
+                            if screen.page_stack[screen.page_stack.len() - 2].display_type
+                                == PageType::Autosave =>
+                        {
+                            save_autosave_as(&mut screen, &mut save_as_warned)
+                        }
                         PageType::SaveAs => {
-                            screen.mode = Mode::SaveAs(screen.active().contents.clone());
+                            screen.mode = Mode::SaveAs(screen.active().contents.to_string());
                             match screen.search_text() {
                                 Some(string) => {
                                     if string.eq("") == false {
                                         let pathname = string.clone();
-                                        let new_text: &String = &screen.text_page().contents;
 
                                         if !Path::new(pathname.as_str()).exists() | save_as_warned {
                                             //if the specified filename does not already exist
-                                            match FileIO::overwrite_to_file(&pathname, new_text) {
-                                                Ok(_) => {
-                                                    screen.file_name = Some(pathname.clone());
+                                            match screen.save(&pathname) {
+                                                // The list of secrets has taken the place of the prompt
+                                                Ok(false) => save_as_warned = false,
+                                                Ok(true) => {
+                                                    screen.set_file_name(&pathname);
                                                     screen.reset_prompt();
                                                     screen.modified = false;
                                                     screen.pop();
@@ -259,10 +567,12 @@ fn main() {
                                                         get_extension(pathname.clone()),
                                                     );
                                                 }
-                                                Err(e) => eprint!(
-                                                    "Failed to save as new file due to error {}",
-                                                    e
-                                                ),
+                                                Err(e) => {
+                                                    screen.active_mut().set_prompt(format!(
+                                                        "Error: {}\nSave As:",
+                                                        e
+                                                    ));
+                                                }
                                             }
                                         } else {
                                             screen.active_mut().set_prompt(String::from("Warning: File Already Exists, Press Enter to Overwrite or choose new file name"));
@@ -283,7 +593,7 @@ fn main() {
                         }
 
                         PageType::Command => {
-                            screen.mode = Mode::Command(screen.active().contents.clone());
+                            screen.mode = Mode::Command(screen.active().contents.to_string());
                             match screen.search_text() {
                                 Some(string) => {
                                     if string.eq("") == false {
@@ -309,6 +619,113 @@ fn main() {
                                         } else if string.to_lowercase().eq("replace") {
                                             screen.pop();
                                             trigger_replace(&mut screen);
+                                        } else if string.to_lowercase().eq("encrypt") {
+                                            screen.pop();
+                                            if !screen.refuse_if_read_only() {
+                                                trigger_set_passphrase(&mut screen);
+                                            }
+                                        } else if string.to_lowercase().eq("decrypt") {
+                                            screen.pop();
+                                            if screen.refuse_if_read_only() {
+                                                continue;
+                                            }
+                                            if screen.encryption.is_some() || screen.gpg.is_some() {
+                                                screen.encryption = None;
+                                                screen.gpg = None;
+                                                screen.modified = true;
+                                                screen.reset_prompt();
+                                            }
+                                        } else if string.to_lowercase().eq("convert to lf") {
+                                            screen.pop();
+                                            if !screen.refuse_if_read_only()
+                                                && !screen
+                                                    .refuse_in_hex_view("Converting line endings")
+                                            {
+                                                screen.convert_line_endings(LineEnding::Lf);
+                                            }
+                                        } else if string.to_lowercase().eq("convert to crlf") {
+                                            screen.pop();
+                                            if !screen.refuse_if_read_only()
+                                                && !screen
+                                                    .refuse_in_hex_view("Converting line endings")
+                                            {
+                                                screen.convert_line_endings(LineEnding::Crlf);
+                                            }
+                                        } else if string.to_lowercase().eq("set encoding") {
+                                            screen.pop();
+                                            if !screen.refuse_if_read_only()
+                                                && !screen.refuse_in_hex_view("Set Encoding")
+                                            {
+                                                trigger_set_encoding(&mut screen);
+                                            }
+                                        } else if string.to_lowercase().eq("toggle bom") {
+                                            screen.pop();
+                                            if screen.refuse_if_read_only()
+                                                || screen.refuse_in_hex_view("Toggle BOM")
+                                            {
+                                                continue;
+                                            }
+                                            if !screen.toggle_bom() {
+                                                let name = screen.text_encoding.name();
+                                                screen.text_page_mut().set_prompt(format!(
+                                                    "{} has no byte order mark",
+                                                    name
+                                                ));
+                                            }
+                                        } else if string.to_lowercase().eq("toggle hex") {
+                                            screen.pop();
+                                            if let Err(e) = screen.toggle_hex() {
+                                                screen.show_error(&e);
+                                            }
+                                        } else if string.to_lowercase().eq("goto offset") {
+                                            screen.pop();
+                                            trigger_goto_offset(&mut screen);
+                                        } else if string.to_lowercase().eq("upper case") {
+                                            screen.pop();
+                                            screen.change_case(true);
+                                        } else if string.to_lowercase().eq("lower case") {
+                                            screen.pop();
+                                            screen.change_case(false);
+                                        } else if string.to_lowercase().eq("copy") {
+                                            screen.pop();
+                                            if screen.active().display_type == PageType::Text {
+                                                screen.copy();
+                                            }
+                                        } else if string.to_lowercase().eq("toggle sensitive") {
+                                            screen.pop();
+                                            screen.toggle_sensitive();
+                                        } else if string.to_lowercase().eq("toggle masking") {
+                                            screen.pop();
+                                            screen.toggle_masking();
+                                        } else if string.to_lowercase().eq("hidden characters") {
+                                            screen.pop();
+                                            if screen.page_stack.len() == 1
+                                                && !screen
+                                                    .refuse_in_hex_view("Listing hidden characters")
+                                                && !screen
+                                                    .refuse_if_lazy("Listing hidden characters")
+                                            {
+                                                screen.add_hidden_chars_page();
+                                            }
+                                        } else if string.to_lowercase().eq("recover autosave") {
+                                            screen.pop();
+                                            if screen.page_stack.len() == 1 {
+                                                screen.show_recovery_page();
+                                            }
+                                        } else if string.to_lowercase().eq("toggle signing") {
+                                            screen.pop();
+                                            screen.toggle_signing();
+                                        } else if string.to_lowercase().eq("encrypt selection") {
+                                            screen.pop();
+                                            trigger_encrypt_selection(&mut screen);
+                                        } else if string.to_lowercase().eq("set lock passphrase") {
+                                            screen.pop();
+                                            trigger_set_lock_passphrase(&mut screen);
+                                        } else if string.to_lowercase().eq("take over lock") {
+                                            screen.pop();
+                                            if let Err(e) = screen.take_over_lock() {
+                                                screen.show_error(&e);
+                                            }
                                         } else{
                                             screen.pop();
                                         }
@@ -322,8 +739,36 @@ fn main() {
                             }
                         }
 
+                        PageType::Find if screen.hex_view() => {
+                            let input = screen.active().contents.to_string();
+                            match hex_editor::parse_bytes(&input) {
+                                Some(pattern) => {
+                                    screen.pop();
+                                    screen.hex_find(pattern, true, false);
+                                }
+                                None => screen.active_mut().set_prompt(String::from(
+                                    "Not a byte sequence, try 7f 45 4c 46 or \"ELF\"\nBytes to find:",
+                                )),
+                            }
+                        }
+                        PageType::GotoOffset => {
+                            let input = screen.active().contents.to_string();
+                            let moved = match (hex_editor::parse_offset(&input), &mut screen.hex) {
+                                (Some(offset), Some(hex)) => hex.goto(offset),
+                                _ => false,
+                            };
+                            if moved {
+                                screen.pop();
+                                screen.refresh_hex();
+                            } else {
+                                screen.active_mut().set_prompt(format!(
+                                    "\"{}\" is not an offset in this file\nOffset (decimal or 0x hex):",
+                                    input.trim()
+                                ));
+                            }
+                        }
                         PageType::Find => {
-                            screen.mode = Mode::Find(screen.active().contents.clone());
+                            screen.mode = Mode::Find(screen.active().contents.to_string());
 
                             // screen.mode = Mode::Find(the_text_that_is_being_searched_for);
                             match screen.search_text() {
@@ -332,11 +777,9 @@ fn main() {
                                 }
                                 Some(str) => {
                                     if str.eq("") == false {
-                                        let number_found = screen
-                                            .text_page()
-                                            .contents
-                                            .matches(&screen.search_text().unwrap())
-                                            .count();
+                                        let text = screen.text_page().contents.to_string();
+                                        let number_found =
+                                            text.matches(&screen.search_text().unwrap()).count();
                                         if number_found > 1 {
                                             screen.text_page_mut().set_prompt(format!(
                                             "Found {} matches: (Ctrl + Left for previous, Ctrl + Right for next, ESC to exit find mode)",
@@ -357,12 +800,12 @@ fn main() {
                                         //Find & Move Cursor operation below
 
                                         indices = get_indices(
-                                            &screen.text_page().contents,
+                                            &text,
                                             &screen.search_text().unwrap(),
                                             number_found,
                                         ); //list of indices where find text occurs
                                         coordinates =
-                                            get_xs_and_ys(indices, &screen.active().contents); //list of (x, y) pairs for moving the cursor
+                                            get_xs_and_ys(indices, &text); //list of (x, y) pairs for moving the cursor
 
                                         let (res1, res2) = find_text(
                                             screen.text_page(),
@@ -387,7 +830,7 @@ fn main() {
                         } //match PageType::Find
                         PageType::ReplaceP1 => {
                             screen.mode = Mode::Replace(match screen.page_stack.last() {
-                                Some(t) => String::from(t.contents.as_str()),
+                                Some(t) => t.contents.to_string(),
                                 None => String::new(),
                             });
 
@@ -418,7 +861,7 @@ fn main() {
                         }
                         PageType::ReplaceP2 => {
                             let to_replace = match screen.page_stack.last() {
-                                Some(t) => String::from(t.contents.as_str()),
+                                Some(t) => t.contents.to_string(),
                                 None => String::new(),
                             };
                             let temp007 = match &screen.mode {
@@ -432,10 +875,12 @@ fn main() {
 
                             if to_replace.eq("") == false {
 
-                                screen.text_page_mut().contents = screen
-                                    .text_page_mut()
+                                let replaced = screen
+                                    .text_page()
                                     .contents
+                                    .to_string()
                                     .replace(temp007.as_str(), to_replace.as_str());
+                                screen.replace_contents(replaced);
                                 screen.pop();
                                 screen
                                     .text_page_mut()
@@ -451,6 +896,132 @@ fn main() {
                                 screen.mode = Mode::Normal;
                             }
                         }
+                        PageType::Passphrase => {
+                            let passphrase = screen.active().contents.to_string();
+                            let pathname = opened_file_path.clone().unwrap_or_default();
+                            match FileIO::read_from_encrypted_file(&pathname, &passphrase) {
+                                Ok((contents, key)) => {
+                                    screen.pop();
+                                    screen.load_contents(contents);
+                                    screen.encryption = Some(key);
+                                    screen.modified = false;
+                                    screen.reset_prompt();
+                                    screen.record_disk_state(&pathname);
+                                    screen.restore_history();
+                                    screen.add_recovery_page();
+                                    screen.add_vault_page();
+                                }
+                                Err(e) => {
+                                    // Lets the user try again
+                                    screen.active_mut().set_contents(String::new());
+                                    screen.key_handler.ip = InsertionPoint::new();
+                                    screen
+                                        .active_mut()
+                                        .set_prompt(format!("{}\nPassphrase:", e));
+                                }
+                            }
+                        }
+                        PageType::VaultPassphrase => {
+                            if let Err(e) = screen.unlock_vault() {
+                                // Lets the user try again
+                                screen
+                                    .active_mut()
+                                    .set_prompt(format!("{}\nVault passphrase:", e));
+                            }
+                        }
+                        PageType::SetPassphrase
+                        | PageType::SetLockPassphrase
+                        | PageType::SetVaultPassphrase => {
+                            let passphrase = screen.active().contents.to_string();
+                            screen.active_mut().set_contents(String::new());
+                            screen.key_handler.ip = InsertionPoint::new();
+                            match screen.pending_passphrase.take() {
+                                _ if passphrase.is_empty() => {
+                                    screen.active_mut().set_prompt(String::from(
+                                        "Passphrase cannot be empty\nNew passphrase:",
+                                    ));
+                                }
+                                None => {
+                                    screen.pending_passphrase = Some(passphrase);
+                                    screen
+                                        .active_mut()
+                                        .set_prompt(String::from("Confirm passphrase:"));
+                                }
+                                Some(first) if first == passphrase => {
+                                    match EncryptionKey::new(&passphrase) {
+                                        Ok(key)
+                                            if screen.active().display_type
+                                                == PageType::SetLockPassphrase =>
+                                        {
+                                            screen.pop();
+                                            screen.lock_key = Some(key);
+                                            screen.text_page_mut().set_prompt(String::from(
+                                                "Lock passphrase set for this session",
+                                            ));
+                                        }
+                                        Ok(key)
+                                            if screen.active().display_type
+                                                == PageType::SetVaultPassphrase =>
+                                        {
+                                            hardening::lock_memory();
+                                            screen.pop();
+                                            screen.create_vault(key);
+                                            screen.reset_prompt();
+                                        }
+                                        Ok(key) => {
+                                            hardening::lock_memory();
+                                            screen.pop();
+                                            screen.encryption = Some(key);
+                                            screen.gpg = None;
+                                            screen.modified = true;
+                                            screen.reset_prompt();
+                                            // Encrypting instead of saving secrets in the clear
+                                            if let Some(pathname) = screen.pending_save.take() {
+                                                match screen.save(&pathname) {
+                                                    Ok(_) => saved_to(&mut screen, &pathname),
+                                                    Err(e) => screen.show_error(&e),
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            screen
+                                                .active_mut()
+                                                .set_prompt(format!("{}\nNew passphrase:", e));
+                                        }
+                                    }
+                                }
+                                Some(_) => {
+                                    screen.active_mut().set_prompt(String::from(
+                                        "Passphrases did not match\nNew passphrase:",
+                                    ));
+                                }
+                            }
+                        }
+                        PageType::Encoding => {
+                            let label = screen.active().contents.to_string();
+                            let result = match TextEncoding::from_label(&label) {
+                                Some(text_encoding) => screen.change_encoding(text_encoding),
+                                None => {
+                                    screen.active_mut().set_prompt(format!(
+                                        "Unknown encoding \"{}\"\nEncoding:",
+                                        label.trim()
+                                    ));
+                                    continue;
+                                }
+                            };
+                            match result {
+                                Ok(_) => {
+                                    screen.pop();
+                                }
+                                Err(e) => {
+                                    screen
+                                        .active_mut()
+                                        .set_prompt(format!("Error: {}\nEncoding:", e));
+                                }
+                            }
+                        }
+                        PageType::Secrets => secrets_choice(&mut screen, KeyCode::Enter),
+                        PageType::HiddenChars => hidden_chars_choice(&mut screen, KeyCode::Enter),
                         _ => {}
                     }
                 }
@@ -468,15 +1039,31 @@ fn main() {
                     screen.mode = Mode::Normal;
                 }
 
+                // Undo and redo on the text page
+                KeyEvent {
+                    code: KeyCode::Char('z'),
+                    modifiers: event::KeyModifiers::CONTROL,
+                } if screen.page_stack.len() == 1 => screen.undo(),
+
+                KeyEvent {
+                    code: KeyCode::Char('y'),
+                    modifiers: event::KeyModifiers::CONTROL,
+                } if screen.page_stack.len() == 1 => screen.redo(),
+
                 // Triggers find screen
                 KeyEvent {
                     code: KeyCode::Char('f'),
                     modifiers: event::KeyModifiers::CONTROL,
                 } => {
-                    if screen.page_stack.len() == 1 {
+                    if screen.page_stack.len() == 1 && !screen.refuse_if_lazy("Find") {
                         // find_display
+                        let prompt = if screen.hex_view() {
+                            "Bytes to find (hex, or \"text\" in quotes):"
+                        } else {
+                            "Text to find:"
+                        };
                         screen.add(PageType::Find);
-                        screen.active_mut().set_prompt(String::from("Text to find:"));
+                        screen.active_mut().set_prompt(String::from(prompt));
                     }
                     if screen.find_mode() {
                         screen.reset_prompt();
@@ -489,7 +1076,11 @@ fn main() {
                     code: KeyCode::Char('r'),
                     modifiers: event::KeyModifiers::CONTROL,
                 } => {
-                    if screen.page_stack.len() == 1 {
+                    if screen.page_stack.len() == 1
+                        && !screen.refuse_if_read_only()
+                        && !screen.refuse_in_hex_view("Replace")
+                        && !screen.refuse_if_lazy("Replace")
+                    {
                         screen.add(PageType::ReplaceP1);
                         screen
                             .active_mut()
@@ -505,6 +1096,35 @@ fn main() {
                     code: KeyCode::Esc,
                     modifiers: event::KeyModifiers::NONE,
                 } => {
+                    if screen.awaiting_passphrase() {
+                        // The file cannot be edited without its passphrase
+                        break;
+                    }
+                    if screen.active().display_type == PageType::SetLockPassphrase
+                        && screen.needs_lock_passphrase()
+                    {
+                        // Nor can a gpg file stay open without a way to unlock it again
+                        break;
+                    }
+                    if matches!(
+                        screen.active().display_type,
+                        PageType::SetPassphrase
+                            | PageType::SetLockPassphrase
+                            | PageType::SetVaultPassphrase
+                    ) {
+                        screen.pending_passphrase = None;
+                        screen.pending_save = None;
+                    }
+                    if screen.active().display_type == PageType::Secrets {
+                        screen.pending_save = None;
+                        screen.secret_findings.clear();
+                    }
+                    if screen.active().display_type == PageType::HiddenChars {
+                        screen.hidden_found.clear();
+                    }
+                    if screen.active().display_type == PageType::Conflict {
+                        screen.dismiss_disk_change();
+                    }
                     if screen.page_stack.len() > 1 {
                         screen.pop();
                     } else {
@@ -527,14 +1147,34 @@ fn main() {
             operations += 1;
         } else {
             operations = 0;
-            if AUTOSAVE {
-                FileIO::auto_save(&opened_file_path, &screen.text_page().contents);
+            if autosave
+                && !screen.awaiting_passphrase()
+                && !screen.read_only()
+                && !screen.hex_view()
+                // Lazily opened files are too big to copy on every autosave
+                && !screen.text_page().contents.is_lazy()
+                // The editor has no key to encrypt the autosaves of a gpg file with
+                && screen.gpg.is_none()
+                // Nor would decrypted values stay encrypted in one
+                && screen.vault.is_none()
+                // The autosave from an earlier session is kept until the user decides about it
+                && screen.recovered_text.is_none()
+            {
+                if let Err(e) = FileIO::auto_save(
+                    &screen.file_name,
+                    &screen.text_page().contents.to_string(),
+                    screen.encryption.as_ref(),
+                ) {
+                    screen.show_error(&e);
+                }
             }
         }
 
         //render to user save question
     }
     // EXIT
+    // Nothing copied in this session is left on the clipboard
+    screen.clipboard.clear();
 }
 
 /*
@@ -544,7 +1184,7 @@ fn main() {
  *  so it can be easily called from the command line.
  */
 fn trigger_find(scr: &mut Screen) {
-    if scr.page_stack.len() == 1 {
+    if scr.page_stack.len() == 1 && !scr.refuse_if_lazy("Find") {
         scr.add(PageType::Find);
         scr.active_mut().set_prompt(String::from("Text to Find"));
     }
@@ -573,7 +1213,10 @@ fn trigger_file_info(scr: &mut Screen, path: &Option<String>, arg: String) {
             None => "",
         });
 
-        scr.add_info_page(String::from(FileIO::get_metadata(&pathname)));
+        match FileIO::get_metadata(&pathname) {
+            Ok(info) => scr.add_info_page(info),
+            Err(e) => scr.show_error(&e),
+        }
     } else {
         scr.pop();
     }
@@ -595,6 +1238,290 @@ fn trigger_saveas(scr: &mut Screen) {
     scr.mode = Mode::Normal;
 }
 
+/*
+ *  This function is called when the user enters the Encrypt command
+ *  from the Command Line screen. It opens a prompt for a new passphrase
+ *  which has to be typed twice before the buffer is encrypted on save.
+ */
+fn trigger_set_passphrase(screen: &mut Screen) {
+    if screen.page_stack.len() == 1 {
+        screen.pending_passphrase = None;
+        screen.add(PageType::SetPassphrase);
+        screen
+            .active_mut()
+            .set_prompt(String::from("New passphrase:"));
+    }
+    screen.mode = Mode::Normal;
+}
+
+/*
+ *  This function is called when the user enters the Set Lock Passphrase command
+ *  from the Command Line screen. The passphrase unlocks sensitive files that are
+ *  not encrypted after the idle lock has covered them.
+ */
+fn trigger_set_lock_passphrase(screen: &mut Screen) {
+    if screen.page_stack.len() == 1 {
+        screen.pending_passphrase = None;
+        screen.add(PageType::SetLockPassphrase);
+        screen
+            .active_mut()
+            .set_prompt(String::from("New lock passphrase:"));
+    }
+    screen.mode = Mode::Normal;
+}
+
+/*
+ *  This function is called when the user enters the Encrypt Selection command
+ *  from the Command Line screen. Every value in a file shares one passphrase, so
+ *  it is asked for once: set the first time, or typed to decrypt the values the
+ *  file already has.
+ */
+fn trigger_encrypt_selection(screen: &mut Screen) {
+    screen.mode = Mode::Normal;
+    if screen.page_stack.len() != 1
+        || screen.refuse_in_hex_view("Encrypting a selection")
+        || screen.refuse_if_read_only()
+    {
+        return;
+    }
+    if screen.key_handler.selection(screen.text_page()).is_none() {
+        screen
+            .text_page_mut()
+            .set_prompt(String::from("Select the text to encrypt first"));
+    } else if screen.vault.is_some() {
+        screen.encrypt_selection();
+    } else if vault::has_tokens(&screen.text_page().contents.to_string()) {
+        screen.add_vault_page();
+    } else {
+        screen.pending_passphrase = None;
+        screen.add(PageType::SetVaultPassphrase);
+        screen
+            .active_mut()
+            .set_prompt(String::from("New vault passphrase:"));
+    }
+}
+
+/*
+ *  This function is called when the user enters the Set Encoding command
+ *  from the Command Line screen. It opens a prompt for the name of the
+ *  encoding the file should be saved in.
+ */
+fn trigger_set_encoding(screen: &mut Screen) {
+    if screen.page_stack.len() == 1 {
+        let current = screen.text_encoding.name();
+        screen.add(PageType::Encoding);
+        screen.active_mut().set_prompt(format!(
+            "Currently {} (e.g. UTF-8, UTF-16LE, UTF-16BE, windows-1252, ISO-8859-1)\nEncoding:",
+            current
+        ));
+    }
+    screen.mode = Mode::Normal;
+}
+
+/*
+ *  This function is called when the user enters the Goto Offset command
+ *  from the Command Line screen. It opens a prompt for the byte offset
+ *  to move the cursor to in hex view.
+ */
+fn trigger_goto_offset(screen: &mut Screen) {
+    if screen.page_stack.len() == 1 && !screen.hex_view() {
+        screen.text_page_mut().set_prompt(String::from(
+            "Goto Offset only works in hex view (run \"Toggle Hex\")",
+        ));
+    } else if screen.page_stack.len() == 1 {
+        screen.add(PageType::GotoOffset);
+        screen
+            .active_mut()
+            .set_prompt(String::from("Offset (decimal or 0x hex):"));
+    }
+    screen.mode = Mode::Normal;
+}
+
+/*
+ *  This function handles the key pressed on the crash recovery page. Recovering
+ *  only replaces the buffer, the file on disk is left alone until the user saves.
+ */
+fn recovery_choice(screen: &mut Screen, input: KeyCode) {
+    let autosave = match &screen.recovered_text {
+        Some(t) => t.clone(),
+        None => return,
+    };
+    match input {
+        KeyCode::Char('r') => {
+            screen.recovered_text = None;
+            screen.pop();
+            screen.replace_contents(autosave);
+            screen.key_handler.ip = InsertionPoint::new();
+            screen.modified = true;
+        }
+        KeyCode::Char('d') => {
+            screen.recovered_text = None;
+            screen.pop();
+            FileIO::delete_auto_save(&screen.file_name);
+        }
+        KeyCode::Char('o') => {
+            // The file stays in the editor and the autosave is opened next to it, to be saved on its own
+            screen.pop();
+            screen.add(PageType::Autosave);
+            screen.active_mut().set_prompt(String::from(
+                "Autosave ([Ctrl+S] save it as a new file, [Esc] return to the file):",
+            ));
+            screen.active_mut().set_contents(autosave);
+        }
+        _ => {}
+    }
+}
+
+/*
+ *  This function saves the autosave opened next to the file under the name typed
+ *  on the Save As page, asking first before a file that exists is written over.
+ */
+fn save_autosave_as(screen: &mut Screen, warned: &mut bool) {
+    let pathname = screen.active().contents.to_string();
+    if pathname.is_empty() {
+        screen.pop();
+        *warned = false;
+        return;
+    }
+    if Path::new(&pathname).exists() && !*warned {
+        screen.active_mut().set_prompt(String::from(
+            "Warning: File Already Exists, Press Enter to Overwrite or choose new file name",
+        ));
+        *warned = true;
+        return;
+    }
+    *warned = false;
+    let text = screen.page_stack[screen.page_stack.len() - 2]
+        .contents
+        .to_string();
+    match screen.save_recovered(&pathname, &text) {
+        Ok(false) => {}
+        Ok(true) => {
+            screen.pop();
+            screen.pop();
+            screen
+                .text_page_mut()
+                .set_prompt(format!("Autosave saved as {}", pathname));
+        }
+        Err(e) => screen
+            .active_mut()
+            .set_prompt(format!("Error: {}\nSave autosave as:", e)),
+    }
+}
+
+/*
+ *  This function handles the key pressed on the page shown when the file was
+ *  changed on disk by another program. Merging only changes the buffer; the
+ *  result still has to be checked and saved by the user.
+ */
+fn conflict_choice(screen: &mut Screen, input: KeyCode) {
+    let pathname = match screen.file_name.clone() {
+        Some(p) => p,
+        None => return,
+    };
+    match input {
+        KeyCode::Char('r') => {
+            screen.pop();
+            if let Err(e) = screen.reload_from_disk() {
+                screen.show_error(&e);
+            }
+        }
+        KeyCode::Char('o') => {
+            screen.pop();
+            match screen.save(&pathname) {
+                Ok(false) => {}
+                Ok(true) => {
+                    screen.modified = false;
+                    screen.reset_prompt();
+                }
+                Err(e) => screen.show_error(&e),
+            }
+        }
+        KeyCode::Char('m') => {
+            screen.pop();
+            if screen.refuse_in_hex_view("Merging") {
+                return;
+            }
+            if screen.text_page().contents.is_lazy() {
+                screen
+                    .text_page_mut()
+                    .set_prompt(String::from("The file is too large to merge"));
+                return;
+            }
+            match screen.merge_with_disk() {
+                Ok(conflicts) => {
+                    screen.key_handler.ip = InsertionPoint::new();
+                    screen.modified = true;
+                    if conflicts == 0 {
+                        screen.reset_prompt();
+                    } else {
+                        screen.text_page_mut().set_prompt(format!(
+                            "Merged: {} conflicts marked with <<<<<<<",
+                            conflicts
+                        ));
+                    }
+                }
+                Err(e) => screen.show_error(&e),
+            }
+        }
+        _ => {}
+    }
+}
+
+/*
+ *  This function handles the key pressed on the list of secrets found in a file
+ *  that was about to be saved unencrypted. Nothing is written unless the user
+ *  saves anyway or encrypts the file first.
+ */
+fn secrets_choice(screen: &mut Screen, input: KeyCode) {
+    let pathname = match screen.pending_save.clone() {
+        Some(p) => p,
+        None => return,
+    };
+    match input {
+        KeyCode::Char('j') | KeyCode::Enter => screen.jump_to_secret(),
+        KeyCode::Char('e') => {
+            // The save happens once the new passphrase has been confirmed
+            screen.pop();
+            screen.secret_findings.clear();
+            trigger_set_passphrase(screen);
+            screen.pending_save = Some(pathname);
+        }
+        KeyCode::Char('s') => {
+            screen.pop();
+            screen.pending_save = None;
+            screen.secret_findings.clear();
+            match screen.save_without_scan(&pathname) {
+                Ok(_) => saved_to(screen, &pathname),
+                Err(e) => screen.show_error(&e),
+            }
+        }
+        _ => {}
+    }
+}
+
+/*
+ *  This function handles the key pressed on the list of hidden and look-alike
+ *  characters in the buffer, which the user can jump to or remove all at once.
+ */
+fn hidden_chars_choice(screen: &mut Screen, input: KeyCode) {
+    match input {
+        KeyCode::Char('j') | KeyCode::Enter => screen.jump_to_hidden_char(),
+        KeyCode::Char('r') => screen.remove_hidden_chars(),
+        _ => {}
+    }
+}
+
+// Updates the screen after the buffer was saved to pathname, which may be a new name for it
+fn saved_to(screen: &mut Screen, pathname: &String) {
+    if screen.file_name.as_ref() != Some(pathname) {
+        screen.set_file_name(pathname);
+        screen.color_struct = Screen::get_color_struct(get_extension(pathname.clone()));
+    }
+    screen.modified = false;
+    screen.reset_prompt();
+}
+
 /*
  *  This function is called when the user enters the Replace command
  *  from the Command Line screen. It essentially does the same thing as the
@@ -602,7 +1529,11 @@ fn trigger_saveas(scr: &mut Screen) {
  *  so it can be easily called from the command line.
  */
 fn trigger_replace(screen: &mut Screen) {
-    if screen.page_stack.len() == 1 {
+    if screen.page_stack.len() == 1
+        && !screen.refuse_if_read_only()
+        && !screen.refuse_in_hex_view("Replace")
+        && !screen.refuse_if_lazy("Replace")
+    {
         screen.add(PageType::ReplaceP1);
         screen
             .active_mut()
@@ -615,13 +1546,19 @@ fn trigger_replace(screen: &mut Screen) {
 }
 
 /*
-    Struct for disabling raw mode on program exit (when instance is dropped)
+    Struct for disabling raw mode and handing the terminal back on program exit (when
+    instance is dropped). It is dropped after the screen, so by then every buffer has
+    been freed and wiped.
 */
 struct TidyUp;
 impl Drop for TidyUp {
     fn drop(&mut self) {
+        // The panic hook has done this already, and clearing the screen again would hide the panic message
+        if std::thread::panicking() {
+            return;
+        }
         terminal::disable_raw_mode().expect("Unable to disable raw mode terminal");
-        Screen::clear_screen().expect("Error");
+        Screen::leave_terminal().expect("Error");
     }
 }
 
@@ -634,9 +1571,10 @@ impl Drop for TidyUp {
 */
 
 fn find_text(disp: &Page, text: &String) -> (Option<usize>, Option<usize>) {
-    match disp.contents.find(text) {
+    let contents = disp.contents.to_string();
+    match contents.find(text) {
         Some(t) => {
-            let (new_x, new_y) = get_newx_newy(&disp.contents, t);
+            let (new_x, new_y) = get_newx_newy(&contents, t);
             return (Some(new_x), Some(new_y));
         }
         None => {
@@ -687,8 +1625,7 @@ fn get_newx_newy(contents: &String, position: usize) -> (usize, usize) {
                 
                 if (total + i) == position {
                     // let s=disp.row_contents.get(y_val).unwrap();
-                    let t = line.unicode_truncate(line[..i].width());
-                    x_val = t.1;
+                    x_val = hidden_chars::prefix_width(line, line[..i].chars().count());
                     break 'outer;
                 }
                 i += c.len_utf8();
@@ -831,6 +1768,11 @@ trait ColorContent {
 */
 
 fn get_extension(full_name: String) -> String {
+    // secrets.yaml.gpg is highlighted as YAML
+    let full_name = match get_gpg_extension(&full_name) {
+        Some(gpg_extension) => full_name[..full_name.len() - gpg_extension.len() - 1].to_owned(),
+        None => full_name,
+    };
     let tokens: Vec<&str> = full_name.split(".").collect();
     if tokens.len() != 2 {
         return "".to_string();
@@ -839,6 +1781,15 @@ fn get_extension(full_name: String) -> String {
     }
 }
 
+// The .gpg, .pgp or .asc suffix of the file name, if it has one
+fn get_gpg_extension(full_name: &str) -> Option<&'static str> {
+    gpg::EXTENSIONS.into_iter().find(|e| {
+        full_name
+            .strip_suffix(e)
+            .is_some_and(|rest| rest.ends_with('.'))
+    })
+}
+
 #[macro_export]
 macro_rules! highlight_struct {
     (
@@ -0,0 +1,115 @@
+use crate::gpg;
+use std::ops::Range;
+use std::path::Path;
+
+// What a masked value is shown as, the same whatever the length of the value
+pub const MASK: &str = "••••";
+
+// Values are masked when their key contains one of these, in any case
+const SENSITIVE_KEYS: [&str; 9] = [
+    "PASSWORD",
+    "PASSWD",
+    "PASSPHRASE",
+    "TOKEN",
+    "SECRET",
+    "API_KEY",
+    "APIKEY",
+    "PRIVATE_KEY",
+    "CREDENTIAL",
+];
+
+// Whether a file's values are masked to begin with: .env, .ini and YAML files
+pub fn masks_file(pathname: &str) -> bool {
+    let name = match Path::new(pathname).file_name() {
+        Some(n) => n.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    // secrets.yaml.gpg is still YAML
+    let name = gpg::EXTENSIONS
+        .iter()
+        .find_map(|e| name.strip_suffix(format!(".{}", e).as_str()))
+        .unwrap_or(&name);
+    name == ".env"
+        || name.starts_with(".env.")
+        || [".env", ".ini", ".yaml", ".yml"]
+            .iter()
+            .any(|e| name.ends_with(e))
+}
+
+/*
+    The byte range of the value on a "key = value", "key: value" or "export KEY=value"
+    line whose key looks like it names a secret. Comments, empty values and YAML
+    values that start on the next line have nothing to mask.
+*/
+pub fn sensitive_value(line: &str) -> Option<Range<usize>> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('#') || trimmed.starts_with(';') {
+        return None;
+    }
+    let separator = line.find(['=', ':'])?;
+    let key = line[..separator].trim();
+    let key = key.strip_prefix("- ").unwrap_or(key).trim_start();
+    let key = key.strip_prefix("export ").unwrap_or(key).trim_start();
+    let key = key.trim_matches(['"', '\'']).to_uppercase();
+    if !SENSITIVE_KEYS.iter().any(|k| key.contains(k)) {
+        return None;
+    }
+    let rest = &line[separator + 1..];
+    let start = separator + 1 + (rest.len() - rest.trim_start().len());
+    let end = line.trim_end().len();
+    (start < end).then_some(start..end)
+}
+
+// The line as it is shown, with its sensitive value replaced by the mask
+pub fn mask_line(line: &str) -> Option<String> {
+    let value = sensitive_value(line)?;
+    Some(format!(
+        "{}{}{}",
+        &line[..value.start],
+        MASK,
+        &line[value.end..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masked_files() {
+        assert!(masks_file("project/.env"));
+        assert!(masks_file(".env.production"));
+        assert!(masks_file("settings.INI"));
+        assert!(masks_file("deploy/values.yml"));
+        assert!(masks_file("secrets.yaml.gpg"));
+        assert!(!masks_file("main.rs"));
+        assert!(!masks_file("environment.txt"));
+        assert!(!masks_file("notes.gpg"));
+    }
+
+    #[test]
+    fn sensitive_values() {
+        let line = "DB_PASSWORD = hunter2  ";
+        assert_eq!(&line[sensitive_value(line).unwrap()], "hunter2");
+        let line = "export GITHUB_TOKEN=ghp_abc";
+        assert_eq!(&line[sensitive_value(line).unwrap()], "ghp_abc");
+        let line = "  - \"api_key\": 'abc'";
+        assert_eq!(&line[sensitive_value(line).unwrap()], "'abc'");
+        // Comments, other keys, empty values and values on the next line
+        assert_eq!(sensitive_value("# password = hunter2"), None);
+        assert_eq!(sensitive_value("; secret = x"), None);
+        assert_eq!(sensitive_value("username = me"), None);
+        assert_eq!(sensitive_value("PASSWORD="), None);
+        assert_eq!(sensitive_value("client_secret:"), None);
+    }
+
+    #[test]
+    fn masked_lines() {
+        assert_eq!(
+            mask_line("password: a-long-password # note").unwrap(),
+            format!("password: {}", MASK)
+        );
+        assert_eq!(mask_line("TOKEN=x\r").unwrap(), format!("TOKEN={}\r", MASK));
+        assert_eq!(mask_line("port: 8080"), None);
+    }
+}
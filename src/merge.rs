@@ -0,0 +1,186 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+// A stretch of the original lines [start, end) that one side replaced with new lines
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+/*
+    Line based three-way merge. Changes made on only one side are taken as they are,
+    identical changes on both sides are taken once, and anything else is written out
+    between conflict markers for the user to sort out. Returns the merged text and
+    the number of conflicts in it.
+*/
+pub fn three_way_merge(original: &str, buffer: &str, disk: &str) -> (String, usize) {
+    let base: Vec<&str> = original.split_inclusive('\n').collect();
+    let ours: Vec<&str> = buffer.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = disk.split_inclusive('\n').collect();
+    let ours_hunks = hunks(&base, &ours);
+    let theirs_hunks = hunks(&base, &theirs);
+
+    let mut output = String::new();
+    let mut conflicts = 0;
+    let mut position = 0;
+    let (mut a, mut b) = (0, 0);
+    while a < ours_hunks.len() || b < theirs_hunks.len() {
+        // Start a cluster at whichever change comes first, then pull in every change touching it
+        let start = match (ours_hunks.get(a), theirs_hunks.get(b)) {
+            (Some(x), Some(y)) => x.start.min(y.start),
+            (Some(x), None) => x.start,
+            (None, Some(y)) => y.start,
+            (None, None) => break,
+        };
+        let mut end = start;
+        let (first_a, first_b) = (a, b);
+        loop {
+            if a < ours_hunks.len() && ours_hunks[a].start <= end {
+                end = end.max(ours_hunks[a].end);
+                a += 1;
+            } else if b < theirs_hunks.len() && theirs_hunks[b].start <= end {
+                end = end.max(theirs_hunks[b].end);
+                b += 1;
+            } else {
+                break;
+            }
+        }
+        for line in &base[position..start] {
+            output += line;
+        }
+        let ours_side = apply(&base, start, end, &ours_hunks[first_a..a]);
+        let theirs_side = apply(&base, start, end, &theirs_hunks[first_b..b]);
+        if first_b == b || ours_side == theirs_side {
+            output += &ours_side;
+        } else if first_a == a {
+            output += &theirs_side;
+        } else {
+            conflicts += 1;
+            output += "<<<<<<< buffer\n";
+            output += &with_newline(ours_side);
+            output += "=======\n";
+            output += &with_newline(theirs_side);
+            output += ">>>>>>> disk\n";
+        }
+        position = end;
+    }
+    for line in &base[position..] {
+        output += line;
+    }
+    (output, conflicts)
+}
+
+// The changes needed to turn the original lines into the edited ones
+fn hunks<'a>(base: &[&str], edited: &[&'a str]) -> Vec<Hunk<'a>> {
+    let mut output: Vec<Hunk<'a>> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, base, edited) {
+        if let DiffOp::Equal { .. } = op {
+            continue;
+        }
+        let (old, new) = (op.old_range(), op.new_range());
+        match output.last_mut() {
+            // Back to back operations belong to the same change
+            Some(last) if last.end == old.start => {
+                last.end = old.end;
+                last.lines.extend_from_slice(&edited[new]);
+            }
+            _ => output.push(Hunk {
+                start: old.start,
+                end: old.end,
+                lines: edited[new].to_vec(),
+            }),
+        }
+    }
+    output
+}
+
+// One side's version of the original lines [start, end)
+fn apply(base: &[&str], start: usize, end: usize, changes: &[Hunk]) -> String {
+    let mut output = String::new();
+    let mut position = start;
+    for hunk in changes {
+        for line in &base[position..hunk.start] {
+            output += line;
+        }
+        for line in &hunk.lines {
+            output += line;
+        }
+        position = hunk.end;
+    }
+    for line in &base[position..end] {
+        output += line;
+    }
+    output
+}
+
+// Keeps conflict markers on their own lines when a side ends without a newline
+fn with_newline(mut text: String) -> String {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn changes_on_one_side_are_taken() {
+        let buffer = "one\nTWO\nthree\nfour\nfive\n";
+        assert_eq!(
+            three_way_merge(ORIGINAL, buffer, ORIGINAL),
+            (buffer.to_owned(), 0)
+        );
+        assert_eq!(
+            three_way_merge(ORIGINAL, ORIGINAL, buffer),
+            (buffer.to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn separate_changes_are_combined() {
+        let buffer = "one\nTWO\nthree\nfour\nfive\n";
+        let disk = "one\ntwo\nthree\nfour\nFIVE\nsix\n";
+        assert_eq!(
+            three_way_merge(ORIGINAL, buffer, disk),
+            (String::from("one\nTWO\nthree\nfour\nFIVE\nsix\n"), 0)
+        );
+    }
+
+    #[test]
+    fn identical_changes_are_taken_once() {
+        let changed = "one\nthree\nfour\nfive\n";
+        assert_eq!(
+            three_way_merge(ORIGINAL, changed, changed),
+            (changed.to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let buffer = "one\nbuffer\nthree\nfour\nfive\n";
+        let disk = "one\ndisk\nthree\nfour\nfive\n";
+        assert_eq!(
+            three_way_merge(ORIGINAL, buffer, disk),
+            (
+                String::from(
+                    "one\n<<<<<<< buffer\nbuffer\n=======\ndisk\n>>>>>>> disk\nthree\nfour\nfive\n"
+                ),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn conflict_markers_stay_on_their_own_lines() {
+        let (merged, conflicts) = three_way_merge("end", "buffer", "disk");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            merged,
+            "<<<<<<< buffer\nbuffer\n=======\ndisk\n>>>>>>> disk\n"
+        );
+    }
+}
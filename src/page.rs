@@ -1,4 +1,5 @@
 use crate::insertion_point::*;
+use crate::text_buffer::TextBuffer;
 
 #[derive(PartialEq)]
 pub enum PageType {
@@ -9,6 +10,20 @@ pub enum PageType {
     ReplaceP2,
     Command,
     SaveAs,
+    Passphrase,
+    SetPassphrase,
+    SetLockPassphrase,
+    SetVaultPassphrase,
+    VaultPassphrase,
+    Locked,
+    Recovery,
+    Autosave,
+    Conflict,
+    Secrets,
+    HiddenChars,
+    Encoding,
+    Hex,
+    GotoOffset,
 }
 
 impl PageType {
@@ -19,9 +34,29 @@ impl PageType {
             PageType::ReplaceP1 => false,
             PageType::ReplaceP2 => false,
             PageType::SaveAs => false,
+            PageType::Passphrase => false,
+            PageType::SetPassphrase => false,
+            PageType::SetLockPassphrase => false,
+            PageType::SetVaultPassphrase => false,
+            PageType::VaultPassphrase => false,
+            PageType::Encoding => false,
+            PageType::GotoOffset => false,
             _ => true,
         }
     }
+
+    // Whether the typed text should be hidden behind '*' when drawn
+    pub fn masks_input(&self) -> bool {
+        matches!(
+            self,
+            PageType::Passphrase
+                | PageType::SetPassphrase
+                | PageType::SetLockPassphrase
+                | PageType::SetVaultPassphrase
+                | PageType::VaultPassphrase
+                | PageType::Locked
+        )
+    }
 }
 
 /*
@@ -29,34 +64,34 @@ impl PageType {
 */
 pub struct Page {
     pub display_type: PageType,
-    pub contents: String,
-    pub row_contents: Vec<String>,
+    pub contents: TextBuffer,
     pub prompt: String,
     pub active_cursor_location: Option<InsertionPoint>,
+    pub active_anchor: Option<InsertionPoint>,
 }
 impl Page {
     pub fn new(display_type: PageType) -> Page {
         Page {
             display_type,
-            contents: String::new(),
-            row_contents: Vec::new(),
+            contents: TextBuffer::new(),
             prompt: String::new(),
             active_cursor_location: None,
+            active_anchor: None,
         }
     }
 
     pub fn new_with_contents(display_type: PageType, contents: String) -> Page {
         Page {
             display_type,
-            contents,
-            row_contents: Vec::new(),
+            contents: TextBuffer::from(contents),
             prompt: String::new(),
             active_cursor_location: None,
+            active_anchor: None,
         }
     }
 
     pub fn set_contents(&mut self, new_contents: String) {
-        self.contents = new_contents;
+        self.contents = TextBuffer::from(new_contents);
     }
 
     pub fn set_prompt(&mut self, new_prompt: String) {
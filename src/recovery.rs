@@ -0,0 +1,43 @@
+use similar::{ChangeTag, TextDiff};
+
+// Lines of unchanged text shown around each change in the recovery diff
+const CONTEXT_LINES: usize = 2;
+
+/*
+    Builds the line diff shown on the recovery and conflict pages, going from the
+    old text to the new one, with "-" marking lines only in the old text and "+"
+    marking lines only in the new one. The names say where each text came from.
+*/
+pub fn line_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut output = String::new();
+    for group in diff.grouped_ops(CONTEXT_LINES) {
+        let first = match group.first() {
+            Some(op) => op,
+            None => continue,
+        };
+        output += &format!(
+            "@@ line {} {}, line {} {} @@\n",
+            first.old_range().start + 1,
+            old_name,
+            first.new_range().start + 1,
+            new_name
+        );
+        for op in &group {
+            for change in diff.iter_changes(op) {
+                let sign = match change.tag() {
+                    ChangeTag::Delete => "- ",
+                    ChangeTag::Insert => "+ ",
+                    ChangeTag::Equal => "  ",
+                };
+                output += sign;
+                output += change.value().trim_end_matches(['\r', '\n']);
+                output += "\n";
+            }
+        }
+    }
+    if output.is_empty() {
+        output += &format!("No differences {} and {}\n", old_name, new_name);
+    }
+    output
+}
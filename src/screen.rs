@@ -1,15 +1,37 @@
-use crate::file_io::FileIO;
+use crate::clipboard::Clipboard;
+use crate::editor_error::EditorError;
+use crate::encryption::EncryptionKey;
+use crate::file_io::{DiskState, FileContents, FileIO};
+use crate::file_lock::{FileLock, LockOwner, LockStatus};
+use crate::gpg::GpgFile;
+use crate::hardening;
+use crate::hex_editor::HexEditor;
+use crate::hidden_chars::{self, Found};
 use crate::insertion_point::*;
 use crate::key_handler::*;
 use crate::language::Language;
+use crate::line_ending::LineEnding;
+use crate::masking;
+use crate::merge;
 use crate::page::*;
+use crate::recovery;
+use crate::secret_scan::{Finding, SecretScanner};
+use crate::signature::{self, SignatureStatus};
+use crate::text_buffer::TextBuffer;
+use crate::text_encoding::TextEncoding;
+use crate::undo::{EditGroup, UndoHistory};
+use crate::vault::{self, Vault};
 use crossterm::event::KeyCode;
 use crossterm::style::*;
 use crossterm::terminal::ClearType;
 use crossterm::{cursor, execute, queue, terminal};
-use std::io::{stdout, Write};
+use ed25519_dalek::SigningKey;
+use std::io::{stdout, Stdout, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::time::Duration;
 use unicode_truncate::UnicodeTruncateStr;
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crossterm::{
     style::{self, Stylize},
@@ -48,12 +70,79 @@ pub struct Screen {
     pub file_name: Option<String>,
     pub modified: bool,
     pub color_struct: ColorWord,
+    pub encryption: Option<EncryptionKey>,
+    pub gpg: Option<GpgFile>,
+    pub pending_passphrase: Option<String>,
+    pub recovered_text: Option<String>,
+    pub disk_state: Option<DiskState>,
+    pub base_text: String,
+    pub dismissed_disk_state: Option<DiskState>,
+    pub file_lock: Option<FileLock>,
+    pub lock_holder: Option<LockOwner>,
+    pub line_ending: LineEnding,
+    // The file had line breaks of other kinds, which are saved in the style of line_ending
+    pub mixed_line_endings: bool,
+    pub text_encoding: TextEncoding,
+    pub hex: Option<HexEditor>,
+    pub history: UndoHistory,
+    pub clipboard: Clipboard,
+    pub idle_lock: Option<Duration>,
+    pub lock_key: Option<EncryptionKey>,
+    pub secret_findings: Vec<Finding>,
+    // What was wrong with the project's patterns file the last time the file was scanned
+    pub patterns_warning: Option<String>,
+    // Why the last save went ahead without looking for secrets, when it did
+    pub scan_skipped: Option<&'static str>,
+    pub pending_save: Option<String>,
+    pub signature: SignatureStatus,
+    pub signing_key: Option<SigningKey>,
+    // Why the file could not be signed or its signature checked the last time it was saved
+    pub signing_problem: Option<String>,
+    pub vault: Option<Vault>,
+    pub mask_values: bool,
+    pub hidden_found: Vec<Found>,
+    // How many hidden or look-alike characters the text had when it was last opened or saved
+    pub hidden_count: usize,
 }
+
+// Minutes without a key press before a sensitive file is locked, unless SFE_IDLE_LOCK_MINUTES says otherwise
+pub const DEFAULT_IDLE_LOCK_MINUTES: f64 = 5.0;
+
+// Commands listed on the command line page
+pub const COMMANDS: [&str; 24] = [
+    "Toggle Highlight",
+    "Find",
+    "Replace",
+    "File Info",
+    "Save As",
+    "Encrypt",
+    "Decrypt",
+    "Take Over Lock",
+    "Convert to LF",
+    "Convert to CRLF",
+    "Set Encoding",
+    "Toggle BOM",
+    "Toggle Hex",
+    "Goto Offset",
+    "Upper Case",
+    "Lower Case",
+    "Copy",
+    "Toggle Sensitive",
+    "Set Lock Passphrase",
+    "Toggle Signing",
+    "Encrypt Selection",
+    "Toggle Masking",
+    "Hidden Characters",
+    "Recover Autosave",
+];
+
 impl Screen {
     pub fn new(file_name: Option<String>, extension: String) -> Self {
+        // Without a terminal, as in tests, the screen is taken to be the usual 80 by 24
         let screen_size = terminal::size()
             .map(|(x, y)| (x as usize, y as usize))
-            .unwrap();
+            .unwrap_or((80, 24));
+        let mask_values = file_name.as_deref().is_some_and(masking::masks_file);
         Self {
             page_stack: Vec::new(),
             key_handler: KeyHandler::new(screen_size),
@@ -61,11 +150,39 @@ impl Screen {
             file_name,
             modified: false,
             color_struct: Screen::get_color_struct(extension),
+            encryption: None,
+            gpg: None,
+            pending_passphrase: None,
+            recovered_text: None,
+            disk_state: None,
+            base_text: String::new(),
+            dismissed_disk_state: None,
+            file_lock: None,
+            lock_holder: None,
+            line_ending: LineEnding::Lf,
+            mixed_line_endings: false,
+            text_encoding: TextEncoding::default(),
+            hex: None,
+            history: UndoHistory::new(),
+            clipboard: Clipboard::from_env(),
+            idle_lock: idle_lock_from_env(),
+            lock_key: None,
+            secret_findings: Vec::new(),
+            patterns_warning: None,
+            scan_skipped: None,
+            pending_save: None,
+            signature: SignatureStatus::Unsigned,
+            signing_key: None,
+            signing_problem: None,
+            vault: None,
+            mask_values,
+            hidden_found: Vec::new(),
+            hidden_count: 0,
         }
     }
 
     pub fn get_color_struct(extension: String) -> ColorWord {
-        let language = FileIO::get_highlights(extension.to_owned());
+        let language = FileIO::get_highlights(extension.to_owned()).unwrap_or(None);
         let color = ColorWord::new(
             None,
             match language {
@@ -111,7 +228,890 @@ impl Screen {
             Some(t) => t.clone(),
             None => String::from("Unsaved File"),
         };
-        self.text_page_mut().set_prompt(name);
+        let format = if self.hex.is_some() {
+            String::from("hex")
+        } else {
+            format!("{}, {}", self.text_encoding, self.line_ending.as_str())
+        };
+        let format = if self.mixed_line_endings && self.hex.is_none() {
+            format!(
+                "{}, mixed line endings are saved as {}",
+                format,
+                self.line_ending.as_str()
+            )
+        } else {
+            format
+        };
+        let name = if self.encryption.is_some() {
+            format!("{} (encrypted) [{}]", name, format)
+        } else if self.gpg.is_some() {
+            format!("{} (gpg) [{}]", name, format)
+        } else {
+            format!("{} [{}]", name, format)
+        };
+        let name = match self.signature.badge() {
+            Some(badge) => format!("{} [{}]", name, badge),
+            None => name,
+        };
+        let name = match &self.signing_problem {
+            Some(problem) => format!("{} [{}]", name, problem),
+            None => name,
+        };
+        let name = match &self.patterns_warning {
+            Some(warning) => format!(
+                "{} [{}; only the built-in secret patterns were used]",
+                name, warning
+            ),
+            None => name,
+        };
+        let name = match self.scan_skipped {
+            Some(reason) => format!("{} [not scanned for secrets: {}]", name, reason),
+            None => name,
+        };
+        let name = if hardening::lock_failed() {
+            format!(
+                "{} [memory could not be locked, decrypted text may be written to swap]",
+                name
+            )
+        } else {
+            name
+        };
+        let name = if self.hidden_count > 0 && self.hex.is_none() {
+            format!(
+                "{} [{} hidden or look-alike character(s), see \"Hidden Characters\"]",
+                name, self.hidden_count
+            )
+        } else {
+            name
+        };
+        match &self.lock_holder {
+            Some(owner) => {
+                let banner = format!(
+                    "{} (read only, being edited by {}; run \"Take Over Lock\" once it has exited)",
+                    name, owner
+                );
+                self.text_page_mut().set_prompt(banner);
+            }
+            None => self.text_page_mut().set_prompt(name),
+        }
+    }
+
+    // Keeps the lock on the open file, or opens it read only if another editor has it
+    pub fn set_lock(&mut self, status: LockStatus) {
+        match status {
+            LockStatus::Acquired(lock) => {
+                self.file_lock = Some(lock);
+                self.lock_holder = None;
+            }
+            LockStatus::HeldBy(owner) => {
+                self.file_lock = None;
+                self.lock_holder = Some(owner);
+            }
+        }
+    }
+
+    // True while another editor holds the lock on the open file
+    pub fn read_only(&self) -> bool {
+        self.lock_holder.is_some()
+    }
+
+    // Tells the user why the text cannot be changed, returning whether it is read only
+    pub fn refuse_if_read_only(&mut self) -> bool {
+        match (&self.lock_holder, &self.file_name) {
+            (Some(owner), Some(name)) => {
+                let error = EditorError::Locked(name.clone(), owner.clone());
+                self.show_error(&error);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /*
+        Takes the lock from an editor that has exited. The file is read again, since the
+        other editor may have saved it after this one opened it.
+    */
+    pub fn take_over_lock(&mut self) -> Result<(), EditorError> {
+        let pathname = match &self.file_name {
+            Some(p) if self.read_only() => p.clone(),
+            _ => return Ok(()),
+        };
+        match FileLock::acquire(&pathname)? {
+            LockStatus::Acquired(lock) => {
+                self.set_lock(LockStatus::Acquired(lock));
+                self.reload_from_disk()
+            }
+            LockStatus::HeldBy(owner) => Err(EditorError::Locked(pathname, owner)),
+        }
+    }
+
+    // Shows a problem on the text page without closing anything
+    pub fn show_error(&mut self, error: &EditorError) {
+        self.text_page_mut().set_prompt(format!("Error: {}", error));
+    }
+
+    // True while the passphrase for the opened file has not been entered yet
+    pub fn awaiting_passphrase(&self) -> bool {
+        self.active().display_type == PageType::Passphrase
+    }
+
+    /*
+        Writes the text page to the given path, but first looks for secrets in a buffer
+        that would be written in the clear. If any are found they are listed for the user
+        to decide about instead, and false is returned without anything being written.
+        Files too large to be held in memory and the hex view are not scanned, which
+        the prompt says.
+    */
+    pub fn save(&mut self, pathname: &String) -> Result<bool, EditorError> {
+        let unencrypted = self.encryption.is_none() && self.gpg.is_none();
+        let skipped = if !unencrypted {
+            None
+        } else if self.hex.is_some() {
+            Some("hex view")
+        } else if self.text_page().contents.is_lazy() {
+            Some("file too large")
+        } else {
+            None
+        };
+        if skipped != self.scan_skipped {
+            self.scan_skipped = skipped;
+            self.reset_prompt();
+        }
+        if unencrypted && skipped.is_none() {
+            // Values that are or will be encrypted on disk are not scanned
+            let mut text = vault::hide_tokens(&self.text_page().contents.to_string());
+            if self.vault.is_some() {
+                text = vault::hide_secrets(&text);
+            }
+            let (scanner, warning) = SecretScanner::for_file(pathname);
+            // A broken patterns file is warned about rather than stopping every save
+            let warned = self.patterns_warning.is_some();
+            self.patterns_warning = warning.map(|e| e.to_string());
+            if warned || self.patterns_warning.is_some() {
+                self.reset_prompt();
+            }
+            let findings = scanner.scan(&text);
+            if !findings.is_empty() {
+                self.add_secrets_page(pathname, findings);
+                return Ok(false);
+            }
+        }
+        self.save_without_scan(pathname)
+    }
+
+    /*
+        Writes the autosave opened next to the file to a new path, with the file's encoding,
+        line endings and key. Once it is on disk the autosave is no longer needed.
+    */
+    pub fn save_recovered(&mut self, pathname: &String, text: &str) -> Result<bool, EditorError> {
+        if self.encryption.is_none() {
+            let (scanner, warning) = SecretScanner::for_file(pathname);
+            self.patterns_warning = warning.map(|e| e.to_string());
+            let findings = scanner.scan(text);
+            if !findings.is_empty() {
+                self.active_mut().set_prompt(format!(
+                    "The autosave contains {} possible secret(s); recover it instead to review them\nSave autosave as:",
+                    findings.len()
+                ));
+                return Ok(false);
+            }
+        }
+        // Held while writing so no other editor has the file open in the meantime
+        let _lock = match FileLock::acquire(pathname)? {
+            LockStatus::HeldBy(owner) => return Err(EditorError::Locked(pathname.clone(), owner)),
+            LockStatus::Acquired(lock) => lock,
+        };
+        let data = self.text_encoding.encode(&self.line_ending.apply(text))?;
+        match &self.encryption {
+            Some(key) => FileIO::overwrite_to_encrypted_file(pathname, &data, key)?,
+            None => FileIO::overwrite_bytes_to_file(pathname, &data)?,
+        };
+        FileIO::delete_auto_save(&self.file_name);
+        self.recovered_text = None;
+        Ok(true)
+    }
+
+    // Writes the text page to the given path, encrypting it if the buffer has a key
+    pub fn save_without_scan(&mut self, pathname: &String) -> Result<bool, EditorError> {
+        // Never write over a file that another editor has open
+        let new_lock = match &self.file_lock {
+            Some(lock) if lock.covers(pathname) => None,
+            _ => match FileLock::acquire(pathname) {
+                Ok(LockStatus::Acquired(lock)) => Some(lock),
+                Ok(LockStatus::HeldBy(owner)) => {
+                    return Err(EditorError::Locked(pathname.clone(), owner))
+                }
+                // No editor can create a lock in a directory the user cannot write to
+                Err(EditorError::PermissionDenied(_)) => None,
+                Err(e) => return Err(e),
+            },
+        };
+        if self.text_page().contents.is_damaged() {
+            return Err(EditorError::InvalidUtf8(
+                self.file_name.clone().unwrap_or_default(),
+            ));
+        }
+        let sealed = match &mut self.vault {
+            Some(vault) => Some(vault.seal(&self.page_stack[0].contents.to_string())?),
+            None => None,
+        };
+        let plain_utf8 = self.hex.is_none()
+            && self.text_encoding == TextEncoding::default()
+            && self.line_ending == LineEnding::Lf;
+        let as_is =
+            plain_utf8 && self.encryption.is_none() && self.gpg.is_none() && sealed.is_none();
+        // Otherwise the whole text is encoded in memory, and a lazy buffer could not read the file once it is overwritten
+        if !as_is && self.text_page().contents.is_lazy() {
+            let text = self.text_page().contents.to_string();
+            self.text_page_mut().contents = TextBuffer::from(text);
+        }
+        match &self.encryption {
+            // The buffer is already exactly what goes on disk, and may be too big to copy
+            None if as_is => {
+                FileIO::overwrite_buffer_to_file(pathname, &mut self.page_stack[0].contents)?
+            }
+            encryption => {
+                let data = match (&self.hex, sealed) {
+                    (Some(hex), _) => hex.bytes.clone(),
+                    (None, Some(text)) => {
+                        self.text_encoding.encode(&self.line_ending.apply(&text))?
+                    }
+                    (None, None) => self.text_encoding.encode(
+                        &self
+                            .line_ending
+                            .apply(&self.text_page().contents.to_string()),
+                    )?,
+                };
+                match (encryption, &self.gpg) {
+                    (Some(key), _) => FileIO::overwrite_to_encrypted_file(pathname, &data, key)?,
+                    (None, Some(file)) => FileIO::overwrite_to_gpg_file(pathname, &data, file)?,
+                    (None, None) => FileIO::overwrite_bytes_to_file(pathname, &data)?,
+                }
+            }
+        };
+        // Once the file is on disk its autosaves are out of date, unless one is still waiting to be recovered
+        if self.recovered_text.is_none() {
+            FileIO::delete_auto_save(&self.file_name);
+            FileIO::delete_auto_save(&Some(pathname.clone()));
+        }
+        self.record_disk_state(pathname);
+        // Every line break on disk is now of the same kind
+        self.mixed_line_endings = false;
+        self.count_hidden_chars();
+        self.store_history(pathname);
+        // The lock follows the buffer to the file it was saved as
+        if let Some(lock) = new_lock {
+            self.set_lock(LockStatus::Acquired(lock));
+        }
+        // The file is already written, so a problem with its signature is only shown
+        let signed = match &self.signing_key {
+            Some(key) => FileIO::sign_file(pathname, key).map_err(|e| format!("not signed: {}", e)),
+            None => Ok(()),
+        };
+        // A file saved without being signed again no longer matches its old signature
+        let badge = self.signature.badge();
+        let checked = self
+            .check_signature(pathname)
+            .map_err(|e| format!("signature not checked: {}", e));
+        let had_problem = self.signing_problem.is_some();
+        self.signing_problem = signed.and(checked).err();
+        if self.signature.badge() != badge || had_problem || self.signing_problem.is_some() {
+            self.reset_prompt();
+        }
+        Ok(true)
+    }
+
+    // Checks the detached signature of the file at pathname against it and the trusted keys
+    pub fn check_signature(&mut self, pathname: &String) -> Result<(), EditorError> {
+        // A signature that matches stays unverified if the trusted keys cannot be read
+        self.signature = SignatureStatus::Unverified;
+        self.signature = FileIO::verify_signature(pathname)?;
+        Ok(())
+    }
+
+    /*
+        Turns on signing on save for a file that was opened with a signature made with a
+        trusted key, if the user has a key, since saving it without one would leave the file
+        with an invalid signature. A file whose signature does not match, or was made with a
+        key that is not trusted, is never signed without the user asking for it, so changes
+        made by someone else are not vouched for.
+    */
+    pub fn resume_signing(&mut self) -> Result<(), EditorError> {
+        if matches!(self.signature, SignatureStatus::Verified(_)) {
+            self.signing_key = FileIO::read_signing_key()?;
+        }
+        Ok(())
+    }
+
+    // Turns signing on save on or off, creating the user's signing key the first time
+    pub fn toggle_signing(&mut self) {
+        if self.signing_key.take().is_some() {
+            self.text_page_mut()
+                .set_prompt(String::from("Files will not be signed on save"));
+            return;
+        }
+        let read = FileIO::read_signing_key().and_then(|key| match key {
+            Some(key) => {
+                let message = format!(
+                    "Files will be signed on save with key {}",
+                    signature::public_key_text(&key)
+                );
+                Ok((key, message))
+            }
+            None => {
+                let (key, path) = FileIO::create_signing_key()?;
+                let message = format!(
+                    "Created signing key {} in {}; add it to {} to trust files signed with it",
+                    signature::public_key_text(&key),
+                    path,
+                    signature::TRUSTED_KEYS_FILE
+                );
+                Ok((key, message))
+            }
+        });
+        match read {
+            Ok((key, message)) => {
+                self.signing_key = Some(key);
+                self.text_page_mut().set_prompt(message);
+            }
+            Err(e) => self.show_error(&e),
+        }
+    }
+
+    // Replaces the buffer with what is on disk now
+    pub fn reload_from_disk(&mut self) -> Result<(), EditorError> {
+        let pathname = match &self.file_name {
+            Some(p) => p.clone(),
+            None => return Ok(()),
+        };
+        let contents = self.read_disk_raw()?;
+        self.load_contents(contents);
+        self.reveal_buffer()?;
+        self.key_handler.ip = InsertionPoint::new();
+        self.refresh_hex();
+        self.record_disk_state(&pathname);
+        self.restore_history();
+        self.modified = false;
+        let checked = self.check_signature(&pathname);
+        self.reset_prompt();
+        checked
+    }
+
+    // Stops asking about the change currently on disk until it changes again
+    pub fn dismiss_disk_change(&mut self) {
+        self.dismissed_disk_state = match &self.file_name {
+            Some(p) => FileIO::get_disk_state(p).unwrap_or(None),
+            None => None,
+        };
+    }
+
+    // Remembers what is on disk now, as the common ancestor for later merges
+    pub fn record_disk_state(&mut self, pathname: &String) {
+        self.disk_state = FileIO::get_disk_state(pathname).unwrap_or(None);
+        // Lazily opened files are too big to keep a second copy of, so they cannot be merged
+        self.base_text = if self.text_page().contents.is_lazy() {
+            String::new()
+        } else {
+            self.text_page().contents.to_string()
+        };
+        self.dismissed_disk_state = None;
+    }
+
+    /*
+        Whether another program changed the file since it was opened or saved. Changes the
+        user already chose to ignore on the conflict page only count when they are saving.
+    */
+    pub fn changed_on_disk(&mut self, saving: bool) -> bool {
+        let pathname = match &self.file_name {
+            Some(p) => p.clone(),
+            None => return false,
+        };
+        let known = match &self.disk_state {
+            Some(d) => d.clone(),
+            None => return false,
+        };
+        if FileIO::disk_stamp_matches(&pathname, &known) {
+            return false;
+        }
+        match FileIO::get_disk_state(&pathname) {
+            Ok(Some(current)) if current.hash == known.hash => {
+                // Touched but not changed
+                self.disk_state = Some(current);
+                false
+            }
+            Ok(current) => saving || current != self.dismissed_disk_state,
+            Err(_) => false,
+        }
+    }
+
+    // Puts a file that was just read in the buffer, in hex view if it is not text
+    pub fn load_contents(&mut self, contents: FileContents) {
+        // Edits made to what was in the buffer before cannot be undone in a different text
+        self.history = UndoHistory::new();
+        self.key_handler.anchor = None;
+        match contents {
+            FileContents::Text(text, text_encoding) => self.load_text(text, text_encoding),
+            FileContents::Binary(bytes) => {
+                self.hex = Some(HexEditor::new(bytes));
+                self.text_page_mut().display_type = PageType::Hex;
+                self.refresh_hex();
+            }
+            FileContents::Lazy(buffer) => {
+                self.hex = None;
+                self.text_page_mut().display_type = PageType::Text;
+                self.text_encoding = TextEncoding::default();
+                self.line_ending = LineEnding::Lf;
+                self.mixed_line_endings = false;
+                self.text_page_mut().contents = buffer;
+            }
+        }
+        self.count_hidden_chars();
+    }
+
+    /*
+        Counts the hidden characters for the prompt. The text is only looked through
+        when it is opened or saved, as lazily opened files are too big to look
+        through at all and going through every file on every key press is slow.
+    */
+    fn count_hidden_chars(&mut self) {
+        self.hidden_count = if self.hex.is_some() || self.text_page().contents.is_lazy() {
+            0
+        } else {
+            hidden_chars::find_all(&self.text_page().contents.to_string()).len()
+        };
+    }
+
+    // Puts text in the buffer, remembering its encoding and line ending style
+    fn load_text(&mut self, text: String, text_encoding: TextEncoding) {
+        self.hex = None;
+        self.text_page_mut().display_type = PageType::Text;
+        self.text_encoding = text_encoding;
+        self.line_ending = LineEnding::detect(&text);
+        self.mixed_line_endings = self.line_ending.converts_others(&text);
+        let text = self.line_ending.normalize(text);
+        self.text_page_mut().set_contents(text);
+    }
+
+    // True while the buffer is shown as hex bytes instead of text
+    pub fn hex_view(&self) -> bool {
+        self.hex.is_some()
+    }
+
+    /*
+        Switches between the text and the hex view of the buffer. Going to hex encodes
+        the text the way it would be saved; going back decodes the bytes as a new file.
+    */
+    pub fn toggle_hex(&mut self) -> Result<(), EditorError> {
+        match self.hex.take() {
+            Some(hex) => {
+                let (text, text_encoding) = TextEncoding::decode_file(&hex.bytes);
+                self.load_contents(FileContents::Text(text, text_encoding));
+                self.key_handler.ip = InsertionPoint::new();
+            }
+            // The bytes would hold the decrypted values, which are only encrypted when saved as text
+            None if self.vault.is_some() => {
+                self.text_page_mut().set_prompt(String::from(
+                    "Hex view is not available while the file has decrypted values",
+                ));
+                return Ok(());
+            }
+            None => {
+                let bytes = self.text_encoding.encode(
+                    &self
+                        .line_ending
+                        .apply(&self.text_page().contents.to_string()),
+                )?;
+                self.load_contents(FileContents::Binary(bytes));
+            }
+        }
+        self.reset_prompt();
+        Ok(())
+    }
+
+    // Redraws the hex dump after its bytes changed and puts the cursor on the current nibble
+    pub fn refresh_hex(&mut self) {
+        let (dump, ip) = match &self.hex {
+            Some(hex) => (hex.dump(), hex.ip()),
+            None => return,
+        };
+        self.text_page_mut().set_contents(dump);
+        self.key_handler.ip = ip;
+    }
+
+    // Tells the user that something only works on text, returning whether the buffer is in hex view
+    pub fn refuse_in_hex_view(&mut self, what: &str) -> bool {
+        if self.hex.is_some() {
+            self.text_page_mut()
+                .set_prompt(format!("{} is not available in hex view", what));
+            true
+        } else {
+            false
+        }
+    }
+
+    // Searching a lazily opened file would mean reading all of it into memory
+    pub fn refuse_if_lazy(&mut self, what: &str) -> bool {
+        if self.text_page().contents.is_lazy() {
+            self.text_page_mut().set_prompt(format!(
+                "{} is not available in a file this large, which is not read in full",
+                what
+            ));
+            true
+        } else {
+            false
+        }
+    }
+
+    // Searches the hex buffer for a byte sequence, starting at the cursor or just after it
+    pub fn hex_find(&mut self, pattern: Vec<u8>, forward: bool, skip_current: bool) {
+        let hex = match &mut self.hex {
+            Some(h) => h,
+            None => return,
+        };
+        let found = if forward {
+            hex.find_next(&pattern, hex.cursor + usize::from(skip_current))
+        } else {
+            hex.find_previous(&pattern)
+        };
+        let count = hex.count_matches(&pattern);
+        hex.last_search = Some(pattern);
+        let prompt = match found {
+            Some(offset) => {
+                hex.goto(offset);
+                format!(
+                    "Found {} matches, at 0x{:08x}: (Ctrl + Left for previous, Ctrl + Right for next)",
+                    count, offset
+                )
+            }
+            None => String::from("Found no matches"),
+        };
+        self.text_page_mut().set_prompt(prompt);
+        self.refresh_hex();
+    }
+
+    // Switches the line endings the buffer is saved with
+    pub fn convert_line_endings(&mut self, line_ending: LineEnding) {
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.modified = true;
+        }
+        self.reset_prompt();
+    }
+
+    // Switches the encoding the buffer is saved in, as long as all of its text can be written in it
+    pub fn change_encoding(&mut self, text_encoding: TextEncoding) -> Result<(), EditorError> {
+        let mut text_encoding = text_encoding;
+        text_encoding.bom = self.text_encoding.bom && text_encoding.supports_bom();
+        text_encoding.encode(&self.text_page().contents.to_string())?;
+        if self.text_encoding != text_encoding {
+            self.text_encoding = text_encoding;
+            self.modified = true;
+        }
+        self.reset_prompt();
+        Ok(())
+    }
+
+    // Adds or removes the byte order mark, returning false for encodings that have none
+    pub fn toggle_bom(&mut self) -> bool {
+        if !self.text_encoding.supports_bom() {
+            return false;
+        }
+        self.text_encoding.bom = !self.text_encoding.bom;
+        self.modified = true;
+        self.reset_prompt();
+        true
+    }
+
+    // The text currently on disk with '\n' line breaks, or nothing if the file was deleted
+    pub fn read_disk_text(&mut self) -> Result<String, EditorError> {
+        let text = match self.read_disk_raw()? {
+            FileContents::Text(text, _) => LineEnding::detect(&text).normalize(text),
+            FileContents::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            FileContents::Lazy(buffer) => buffer.to_string(),
+        };
+        // Compared with the buffer, so its encrypted values are shown the way the buffer shows them
+        match &mut self.vault {
+            Some(vault) => vault.reveal(&text),
+            None => Ok(text),
+        }
+    }
+
+    // Shows the encrypted values of a buffer that was just read from disk decrypted again
+    fn reveal_buffer(&mut self) -> Result<(), EditorError> {
+        let text = self.text_page().contents.to_string();
+        let revealed = match &mut self.vault {
+            Some(vault) => vault.reveal(&text)?,
+            None => return Ok(()),
+        };
+        self.text_page_mut().set_contents(revealed);
+        self.count_hidden_chars();
+        Ok(())
+    }
+
+    // Asks for the passphrase of the file's encrypted values, if it has any
+    pub fn add_vault_page(&mut self) {
+        if self.hex.is_some()
+            || self.text_page().contents.is_lazy()
+            || !vault::has_tokens(&self.text_page().contents.to_string())
+        {
+            return;
+        }
+        self.add(PageType::VaultPassphrase);
+        self.active_mut().set_prompt(String::from(
+            "The file has encrypted values ([Esc] leaves them encrypted)\nVault passphrase:",
+        ));
+    }
+
+    /*
+        Decrypts the file's values with the passphrase typed on the vault page. The
+        decrypted text takes the place of what was read, so it is not an edit that
+        can be undone, and the history kept for the encrypted text no longer fits it.
+    */
+    pub fn unlock_vault(&mut self) -> Result<(), EditorError> {
+        let passphrase = self.active().contents.to_string();
+        self.active_mut().set_contents(String::new());
+        self.key_handler.ip = InsertionPoint::new();
+        let (mut vault, revealed) =
+            Vault::unlock(&passphrase, &self.text_page().contents.to_string())?;
+        self.base_text = vault.reveal(&self.base_text)?;
+        self.vault = Some(vault);
+        self.pop();
+        self.text_page_mut().set_contents(revealed);
+        self.key_handler.ip = InsertionPoint::new();
+        self.key_handler.anchor = None;
+        self.history = UndoHistory::new();
+        self.count_hidden_chars();
+        Ok(())
+    }
+
+    /*
+        Starts encrypting values in a file that has none yet. The file's own ⟦s and
+        ⟧s are escaped first, as one step that can be undone; that loses the
+        selection, so it has to be made again.
+    */
+    pub fn create_vault(&mut self, key: EncryptionKey) {
+        self.vault = Some(Vault::new(key));
+        self.base_text = vault::escape(&self.base_text);
+        let text = self.text_page().contents.to_string();
+        let escaped = vault::escape(&text);
+        if escaped == text {
+            self.encrypt_selection();
+            return;
+        }
+        self.replace_contents(escaped);
+        self.modified = true;
+        self.text_page_mut().set_prompt(String::from(
+            "The file's own ⟦ and ⟧ were escaped with '\\'; select the text to encrypt again",
+        ));
+    }
+
+    /*
+        Marks the selected text as a value to encrypt by putting it between ⟦ and ⟧,
+        as one step that can be undone. It is encrypted when the file is saved.
+    */
+    pub fn encrypt_selection(&mut self) {
+        let range = match self.key_handler.selection(self.text_page()) {
+            Some(r) => r,
+            None => {
+                self.text_page_mut()
+                    .set_prompt(String::from("Select the text to encrypt first"));
+                return;
+            }
+        };
+        let text = self.text_page().contents.slice(range.clone());
+        if vault::has_markers(&text) {
+            self.text_page_mut().set_prompt(String::from(
+                "The selection overlaps a value that is already encrypted",
+            ));
+            return;
+        }
+        /*
+            The '\\'s at the end of the selection, and any right before it, would escape
+            the markers put around it, so they are doubled like the escapes of a value.
+        */
+        let buffer = &self.text_page().contents;
+        let mut start = range.start;
+        while start > 0 && buffer.slice(start - 1..start) == "\\" {
+            start -= 1;
+        }
+        let mut marked = "\\".repeat((range.start - start) * 2);
+        marked.push(vault::SECRET_START);
+        marked.push_str(&text);
+        marked.extend(std::iter::repeat_n(
+            '\\',
+            text.len() - text.trim_end_matches('\\').len(),
+        ));
+        marked.push(vault::SECRET_END);
+        let cursor_before = self.key_handler.ip.clone();
+        let ((x, y), (end_x, end_y)) = (buffer.position(start), buffer.position(range.end));
+        self.key_handler.anchor = Some(InsertionPoint { x, y });
+        self.key_handler.ip = InsertionPoint { x: end_x, y: end_y };
+        self.key_handler
+            .insert_text(&marked, &mut self.page_stack[0]);
+        self.modified = true;
+        let edits = self.text_page_mut().contents.take_edits();
+        let cursor_after = self.key_handler.ip.clone();
+        self.history
+            .record(edits, cursor_before, cursor_after, None);
+        self.reset_prompt();
+    }
+
+    fn read_disk_raw(&self) -> Result<FileContents, EditorError> {
+        let pathname = match &self.file_name {
+            Some(p) => p,
+            None => return Ok(FileContents::Text(String::new(), self.text_encoding)),
+        };
+        let read = match &self.gpg {
+            Some(_) => FileIO::read_gpg_file(pathname).map(|(contents, _)| contents),
+            None => FileIO::read_decoded(pathname, self.encryption.as_ref()),
+        };
+        match read {
+            Err(EditorError::NotFound(_)) => {
+                Ok(FileContents::Text(String::new(), self.text_encoding))
+            }
+            other => other,
+        }
+    }
+
+    // Shows how the file on disk differs from the buffer and asks what to do about it
+    pub fn add_conflict_page(&mut self) {
+        let diff = if self.text_page().contents.is_lazy() {
+            String::from(
+                "The file on disk differs from the buffer. It is too large to compare or merge.",
+            )
+        } else {
+            match self.read_disk_text() {
+                Ok(_) if self.hex.is_some() => String::from(
+                    "The bytes on disk differ from the buffer. Binary files cannot be merged.",
+                ),
+                Ok(disk) => recovery::line_diff(
+                    &self.text_page().contents.to_string(),
+                    &disk,
+                    "in the buffer",
+                    "on disk",
+                ),
+                Err(e) => format!("The file on disk could not be read: {}", e),
+            }
+        };
+        let name = self.file_name.clone().unwrap_or_default();
+        self.add(PageType::Conflict);
+        self.active_mut().set_prompt(format!(
+            "{} was changed on disk by another program since it was opened\n(r) Reload from disk   (o) Overwrite with the buffer   (m) Three-way merge   [Esc] decide later",
+            name
+        ));
+        self.active_mut().set_contents(diff);
+    }
+
+    // Lists the possible secrets found in the buffer and asks what to do before saving it to pathname
+    pub fn add_secrets_page(&mut self, pathname: &String, findings: Vec<Finding>) {
+        // The list takes the place of the prompt the save was started from
+        while self.page_stack.len() > 1 {
+            self.pop();
+        }
+        let list: Vec<String> = findings
+            .iter()
+            .map(|f| format!("line {}: {}", f.line + 1, f.kind))
+            .collect();
+        let count = findings.len();
+        self.pending_save = Some(pathname.clone());
+        self.secret_findings = findings;
+        self.add(PageType::Secrets);
+        self.active_mut().set_prompt(format!(
+            "{} possible secret(s) found, {} would be saved unencrypted\n(j/Enter) Jump to the finding under the cursor   (e) Encrypt instead   (s) Save anyway   [Esc] cancel",
+            count, pathname
+        ));
+        self.active_mut().set_contents(list.join("\n"));
+    }
+
+    // Lists the characters in the buffer that are shown as placeholders, with what each one does
+    pub fn add_hidden_chars_page(&mut self) {
+        let found = hidden_chars::find_all(&self.text_page().contents.to_string());
+        self.hidden_count = found.len();
+        if found.is_empty() {
+            self.reset_prompt();
+            self.text_page_mut()
+                .set_prompt(String::from("No hidden or look-alike characters found"));
+            return;
+        }
+        let list: Vec<String> = found.iter().map(hidden_chars::describe).collect();
+        self.add(PageType::HiddenChars);
+        self.active_mut().set_prompt(format!(
+            "{} hidden or look-alike character(s) found\n(j/Enter) Jump to the one under the cursor   (r) Remove them all   [Esc] close",
+            found.len()
+        ));
+        self.active_mut().set_contents(list.join("\n"));
+        self.hidden_found = found;
+    }
+
+    // Closes the list of hidden characters and moves the cursor to the one that was selected in it
+    pub fn jump_to_hidden_char(&mut self) {
+        let index = self.key_handler.ip.y;
+        self.pop();
+        if let Some(found) = self.hidden_found.get(index) {
+            self.key_handler.anchor = None;
+            self.key_handler.ip = InsertionPoint {
+                x: found.column,
+                y: found.line,
+            };
+        }
+        self.hidden_found.clear();
+    }
+
+    /*
+        Takes the hidden characters out of the buffer and replaces look-alikes with
+        the ASCII they look like, as one step that can be undone.
+    */
+    pub fn remove_hidden_chars(&mut self) {
+        self.pop();
+        let count = self.hidden_found.len();
+        self.hidden_found.clear();
+        if self.refuse_if_read_only() {
+            return;
+        }
+        let cleaned = hidden_chars::remove_all(&self.text_page().contents.to_string());
+        self.replace_contents(cleaned);
+        // The line the cursor is on may have become shorter
+        let line = self.text_page().contents.line(self.key_handler.ip.y);
+        self.key_handler.ip.x = hidden_chars::truncate(&line, self.key_handler.ip.x).1;
+        self.modified = true;
+        self.hidden_count = 0;
+        self.text_page_mut().set_prompt(format!(
+            "Removed {} hidden or look-alike character(s)",
+            count
+        ));
+    }
+
+    // Closes the list of secrets and moves the cursor to the one that was selected in it
+    pub fn jump_to_secret(&mut self) {
+        let index = self.key_handler.ip.y;
+        self.pop();
+        self.pending_save = None;
+        if let Some(finding) = self.secret_findings.get(index) {
+            self.key_handler.ip = InsertionPoint {
+                x: finding.column,
+                y: finding.line,
+            };
+        }
+        self.secret_findings.clear();
+    }
+
+    // Merges the changes made on disk into the buffer, marking overlapping edits as conflicts
+    pub fn merge_with_disk(&mut self) -> Result<usize, EditorError> {
+        let disk = self.read_disk_text()?;
+        let (merged, conflicts) = merge::three_way_merge(
+            &self.base_text,
+            &self.text_page().contents.to_string(),
+            &disk,
+        );
+        self.replace_contents(merged);
+        // The disk version is now part of the buffer, so it becomes the new common ancestor
+        self.disk_state = match &self.file_name {
+            Some(p) => FileIO::get_disk_state(p).unwrap_or(None),
+            None => None,
+        };
+        self.base_text = disk;
+        self.dismissed_disk_state = None;
+        Ok(conflicts)
     }
 
     pub fn push(&mut self, page: Page) {
@@ -128,15 +1128,17 @@ impl Screen {
                 .unwrap()
                 .clone();
             self.active_mut().active_cursor_location = None;
+            self.key_handler.anchor = self.active_mut().active_anchor.take();
             Some(temp)
         } else {
             None
         }
     }
 
-    // Saves the location of the cursor on the screen, creates a new display, resets the cursor location to 0, 0.
+    // Saves the location of the cursor and selection on the screen, creates a new display, resets the cursor location to 0, 0.
     pub fn add(&mut self, display_type: PageType) {
         self.active_mut().active_cursor_location = Some(self.key_handler.ip.clone());
+        self.active_mut().active_anchor = self.key_handler.anchor.take();
         self.key_handler.ip = InsertionPoint::new();
         self.push(Page::new(display_type));
     }
@@ -151,6 +1153,61 @@ impl Screen {
         self.active_mut().set_contents(help_text);
     }
 
+    /*
+        Looks for an autosave of the open file (or of an unnamed buffer) left behind by a
+        session that did not exit cleanly, and if it differs from what was loaded shows the
+        timestamps of both and a diff so the user can pick which one to keep.
+    */
+    pub fn add_recovery_page(&mut self) {
+        // Autosaves belong to the editor holding the lock, and binary buffers have none
+        if self.read_only() || self.hex.is_some() || !FileIO::check_for_auto_save(&self.file_name) {
+            return;
+        }
+        let autosave = match FileIO::read_auto_save(&self.file_name, self.encryption.as_ref()) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        if autosave == self.text_page().contents.to_string() {
+            FileIO::delete_auto_save(&self.file_name);
+            return;
+        }
+        self.recovered_text = Some(autosave);
+        self.show_recovery_page();
+    }
+
+    /*
+        Shows the autosave found when the file was opened next to the file. The autosave
+        is kept, and not written over, until the user recovers or discards it.
+    */
+    pub fn show_recovery_page(&mut self) {
+        let autosave = match &self.recovered_text {
+            Some(t) => t.clone(),
+            None => {
+                self.text_page_mut()
+                    .set_prompt(String::from("There is no autosave to recover"));
+                return;
+            }
+        };
+        let autosave_time =
+            FileIO::get_auto_save_time(&self.file_name).unwrap_or(String::from("unknown"));
+        let file_time = match &self.file_name {
+            Some(f) => FileIO::get_modified_time(Path::new(f)).unwrap_or(String::from("unknown")),
+            None => String::from("never saved"),
+        };
+        let diff = recovery::line_diff(
+            &self.text_page().contents.to_string(),
+            &autosave,
+            "on disk",
+            "in the autosave",
+        );
+        self.add(PageType::Recovery);
+        self.active_mut().set_prompt(format!(
+            "An autosave from an earlier session was found\nAutosave written: {}\nFile on disk modified: {}\n(r) Recover autosave   (d) Discard autosave   (o) Open both   [Esc] decide later with \"Recover Autosave\"",
+            autosave_time, file_time
+        ));
+        self.active_mut().set_contents(diff);
+    }
+
     pub fn add_info_page(&mut self, info: String) {
         self.add(PageType::Info);
         self.active_mut().set_contents(info);
@@ -161,19 +1218,391 @@ impl Screen {
     }
 
     pub fn move_ip(&mut self, direction: KeyCode) {
+        self.key_handler.anchor = None;
         self.key_handler
-            .move_ip(direction, self.page_stack.first_mut().unwrap());
+            .move_ip(direction, self.page_stack.last_mut().unwrap());
+        self.history.break_group();
+    }
+
+    // Moves the cursor on the text page, selecting the text it passes over
+    pub fn select(&mut self, direction: KeyCode) {
+        self.key_handler
+            .select(direction, self.page_stack.last_mut().unwrap());
+        self.history.break_group();
     }
 
     pub fn insertion(&mut self, input: KeyCode) {
+        let cursor_before = self.key_handler.ip.clone();
+        let selecting = self.key_handler.selection(self.active()).is_some();
         self.key_handler
             .insertion(input, self.page_stack.last_mut().unwrap());
+        let edits = self.active_mut().contents.take_edits();
+        // Only the file itself has an undo history, not the prompts typed into on top of it
+        if self.page_stack.len() == 1 && self.active().display_type == PageType::Text {
+            // Changes to a selection are steps of their own
+            let group = match input {
+                _ if selecting => None,
+                KeyCode::Char(_) | KeyCode::Tab => Some(EditGroup::Typing),
+                KeyCode::Backspace | KeyCode::Delete => Some(EditGroup::Deleting),
+                _ => None,
+            };
+            let cursor_after = self.key_handler.ip.clone();
+            self.history
+                .record(edits, cursor_before, cursor_after, group);
+        }
+    }
+
+    // Turns the selected text into upper or lower case, as one step that can be undone
+    pub fn change_case(&mut self, upper: bool) {
+        if self.refuse_in_hex_view("Changing case") || self.refuse_if_read_only() {
+            return;
+        }
+        let cursor_before = self.key_handler.ip.clone();
+        if !self.key_handler.change_case(upper, &mut self.page_stack[0]) {
+            self.text_page_mut()
+                .set_prompt(String::from("Select some text first"));
+            return;
+        }
+        self.modified = true;
+        let edits = self.text_page_mut().contents.take_edits();
+        let cursor_after = self.key_handler.ip.clone();
+        self.history
+            .record(edits, cursor_before, cursor_after, None);
+    }
+
+    // Text copied out of an encrypted or gpg file, or one marked sensitive, does not stay on the clipboard
+    pub fn is_sensitive(&self) -> bool {
+        self.encryption.is_some()
+            || self.gpg.is_some()
+            || self.vault.is_some()
+            || self
+                .file_name
+                .as_ref()
+                .is_some_and(|name| FileIO::is_marked_sensitive(name))
+    }
+
+    // Puts the selected text on the clipboard, returning false when nothing is selected
+    pub fn copy(&mut self) -> bool {
+        let range = match self.key_handler.selection(self.text_page()) {
+            Some(r) => r,
+            None => {
+                self.text_page_mut()
+                    .set_prompt(String::from("Select some text first"));
+                return false;
+            }
+        };
+        let text = self.text_page().contents.slice(range);
+        let sensitive = self.is_sensitive();
+        self.clipboard.copy(text, sensitive);
+        if sensitive {
+            let seconds = self.clipboard.clear_after().as_secs();
+            self.text_page_mut().set_prompt(format!(
+                "Copied from a sensitive file; the clipboard is cleared in {} seconds",
+                seconds
+            ));
+        }
+        true
+    }
+
+    // Moves the selected text onto the clipboard, as one step that can be undone
+    pub fn cut(&mut self) {
+        if self.refuse_in_hex_view("Cut") || self.refuse_if_read_only() {
+            return;
+        }
+        if self.copy() {
+            self.modified = true;
+            self.insertion(KeyCode::Delete);
+        }
+    }
+
+    // Puts the clipboard in the text page at the cursor, in place of the selection
+    pub fn paste(&mut self) {
+        if self.clipboard.paste().is_none() {
+            self.text_page_mut()
+                .set_prompt(String::from("The clipboard is empty"));
+            return;
+        }
+        if self.refuse_if_read_only() {
+            return;
+        }
+        let cursor_before = self.key_handler.ip.clone();
+        let text = self.clipboard.paste().unwrap_or_default();
+        self.key_handler.insert_text(text, &mut self.page_stack[0]);
+        self.modified = true;
+        let edits = self.text_page_mut().contents.take_edits();
+        let cursor_after = self.key_handler.ip.clone();
+        self.history
+            .record(edits, cursor_before, cursor_after, None);
+    }
+
+    // Gives the buffer the name it was saved as; a new name decides again whether values are masked
+    pub fn set_file_name(&mut self, pathname: &str) {
+        if self.file_name.as_deref() != Some(pathname) {
+            self.file_name = Some(pathname.to_owned());
+            self.mask_values = masking::masks_file(pathname);
+        }
+    }
+
+    // Shows the values of password, token and secret keys, or masks them again
+    pub fn toggle_masking(&mut self) {
+        self.mask_values = !self.mask_values;
+        let prompt = if self.mask_values {
+            "Sensitive values are masked except on the cursor line"
+        } else {
+            "Sensitive values are shown"
+        };
+        self.text_page_mut().set_prompt(String::from(prompt));
+    }
+
+    // Marks the open file as sensitive, or takes the mark off again
+    pub fn toggle_sensitive(&mut self) {
+        let name = match &self.file_name {
+            Some(n) => n.clone(),
+            None => {
+                self.text_page_mut()
+                    .set_prompt(String::from("Save the file before marking it sensitive"));
+                return;
+            }
+        };
+        let sensitive = !FileIO::is_marked_sensitive(&name);
+        match FileIO::mark_sensitive(&name, sensitive) {
+            Ok(_) if sensitive => {
+                let seconds = self.clipboard.clear_after().as_secs();
+                self.text_page_mut().set_prompt(format!(
+                    "Marked sensitive: text copied from it is cleared from the clipboard after {} seconds",
+                    seconds
+                ));
+            }
+            Ok(_) => self
+                .text_page_mut()
+                .set_prompt(String::from("No longer marked sensitive")),
+            Err(e) => self.show_error(&e),
+        }
+    }
+
+    /*
+        Runs the timers between key presses, given how long it has been since the last
+        one, and returns whether the screen has to be drawn again.
+    */
+    pub fn tick(&mut self, idle: Duration) -> bool {
+        let mut changed = false;
+        if self.clipboard.expire() {
+            self.text_page_mut()
+                .set_prompt(String::from("Clipboard cleared"));
+            changed = true;
+        }
+        if self.idle_lock.is_some_and(|after| idle >= after) && self.can_lock() {
+            self.lock();
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn locked(&self) -> bool {
+        self.active().display_type == PageType::Locked
+    }
+
+    /*
+        Encrypted files are unlocked with their own passphrase, and files with decrypted
+        values with the vault's. Other sensitive files have none, so they are only
+        locked once a lock passphrase has been set.
+    */
+    fn can_lock(&self) -> bool {
+        !self.locked()
+            && !self.awaiting_passphrase()
+            && (self.encryption.is_some()
+                || self.vault.is_some()
+                || (self.lock_key.is_some() && self.is_sensitive()))
+    }
+
+    /*
+        gpg asks for the passphrase of a gpg file itself, so the editor would have nothing
+        to unlock it with after the idle lock. A lock passphrase is chosen when it is opened.
+    */
+    pub fn needs_lock_passphrase(&self) -> bool {
+        self.gpg.is_some() && self.lock_key.is_none() && self.idle_lock.is_some()
+    }
+
+    // Covers everything on screen until the passphrase is entered again
+    pub fn lock(&mut self) {
+        self.add(PageType::Locked);
+        self.active_mut().set_prompt(String::from(
+            "Locked while idle, Ctrl+W quits without saving\nPassphrase:",
+        ));
+    }
+
+    // Checks the passphrase typed on the lock screen, going back to where the user was if it is right
+    pub fn unlock(&mut self) {
+        let passphrase = self.active().contents.to_string();
+        self.active_mut().set_contents(String::new());
+        self.key_handler.ip = InsertionPoint::new();
+        let key = self
+            .encryption
+            .as_ref()
+            .or(self.vault.as_ref().map(Vault::key))
+            .or(self.lock_key.as_ref());
+        if key.is_some_and(|k| k.matches(&passphrase)) {
+            self.pop();
+        } else {
+            self.active_mut().set_prompt(String::from(
+                "Wrong passphrase, Ctrl+W quits without saving\nPassphrase:",
+            ));
+        }
+    }
+
+    /*
+        Lets go of the text without asking for the passphrase, for quitting from the lock
+        screen. Nothing is saved, and the allocator overwrites every buffer that is freed
+        here, so no plaintext is left behind in memory.
+    */
+    pub fn discard(&mut self) {
+        for page in self.page_stack.iter_mut() {
+            page.set_contents(String::new());
+        }
+        self.history = UndoHistory::new();
+        self.base_text = String::new();
+        self.recovered_text = None;
+        self.hex = None;
+        self.vault = None;
+        self.encryption = None;
+        self.lock_key = None;
+        self.clipboard.clear();
+    }
+
+    // Replaces all of the text page, as one step that can be undone
+    pub fn replace_contents(&mut self, text: String) {
+        self.key_handler.anchor = None;
+        let cursor_before = self.key_handler.ip.clone();
+        let buffer = &mut self.text_page_mut().contents;
+        let end = buffer.len_chars();
+        buffer.remove(0..end);
+        buffer.insert(0, &text);
+        let edits = buffer.take_edits();
+        let cursor_after = self.key_handler.ip.clone();
+        self.history
+            .record(edits, cursor_before, cursor_after, None);
+    }
+
+    /*
+        Keeps the undo history with the file that was just saved, so the edits can
+        still be undone after the editor is closed. The history is tied to the hash
+        of the saved file, which restore_history checks before using it.
+    */
+    fn store_history(&self, pathname: &str) {
+        let path = Some(pathname.to_owned());
+        /*
+            The editor has no key to keep the edits of a gpg file, or of decrypted values,
+            out of sight with, so a history kept before either was opened is dropped too.
+        */
+        if self.gpg.is_some() || self.vault.is_some() {
+            FileIO::delete_undo_history(&path);
+            return;
+        }
+        let hash = match &self.disk_state {
+            Some(d) => d.hash,
+            None => return,
+        };
+        if self.history.is_empty() {
+            FileIO::delete_undo_history(&path);
+            return;
+        }
+        // Losing the history is not worth failing the save over
+        let _ = FileIO::save_undo_history(
+            &path,
+            &self.history.to_bytes(&hash),
+            self.encryption.as_ref(),
+        );
+    }
+
+    // Picks up the undo history an earlier session saved, unless the file was changed since
+    pub fn restore_history(&mut self) {
+        let hash = match &self.disk_state {
+            Some(d) => d.hash,
+            None => return,
+        };
+        // The history belongs to the editor holding the lock, and hex edits have none
+        if self.read_only() || self.hex.is_some() {
+            return;
+        }
+        // Nor do gpg files or decrypted values, whose edits would be left in the clear
+        if self.gpg.is_some() || self.vault.is_some() {
+            FileIO::delete_undo_history(&self.file_name);
+            return;
+        }
+        let data = match FileIO::read_undo_history(&self.file_name, self.encryption.as_ref()) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        match UndoHistory::from_bytes(&data, &hash) {
+            Some(history) => self.history = history,
+            None => FileIO::delete_undo_history(&self.file_name),
+        }
+    }
+
+    // Takes back the last change to the text page
+    pub fn undo(&mut self) {
+        if self.refuse_in_hex_view("Undo") || self.refuse_if_read_only() {
+            return;
+        }
+        match self.history.undo(&mut self.page_stack[0].contents) {
+            Some(ip) => {
+                self.key_handler.ip = ip;
+                self.key_handler.anchor = None;
+                self.modified = true;
+            }
+            None => self
+                .text_page_mut()
+                .set_prompt(String::from("Nothing to undo")),
+        }
+    }
+
+    // Makes the last undone change again
+    pub fn redo(&mut self) {
+        if self.refuse_in_hex_view("Redo") || self.refuse_if_read_only() {
+            return;
+        }
+        match self.history.redo(&mut self.page_stack[0].contents) {
+            Some(ip) => {
+                self.key_handler.ip = ip;
+                self.key_handler.anchor = None;
+                self.modified = true;
+            }
+            None => self
+                .text_page_mut()
+                .set_prompt(String::from("Nothing to redo")),
+        }
     }
 
     pub fn clear_screen() -> crossterm::Result<()> {
         execute!(stdout(), terminal::Clear(ClearType::All))?;
         execute!(stdout(), cursor::MoveTo(0, 0))
     }
+
+    // Draws the editor on the terminal's alternate screen, under a title that does not name the file
+    pub fn enter_terminal() -> crossterm::Result<()> {
+        execute!(
+            stdout(),
+            terminal::EnterAlternateScreen,
+            terminal::SetTitle("SecureFileEditor")
+        )
+    }
+
+    /*
+        Hands the terminal back with nothing from the file left on it: the alternate
+        screen is cleared before going back to the primary one, the scrollback is
+        purged, the visible screen cleared and the window title blanked.
+    */
+    pub fn leave_terminal() -> crossterm::Result<()> {
+        execute!(
+            stdout(),
+            terminal::Clear(ClearType::All),
+            terminal::LeaveAlternateScreen,
+            terminal::Clear(ClearType::Purge),
+            terminal::SetTitle(""),
+            cursor::Show
+        )?;
+        Screen::clear_screen()
+    }
     pub fn render(&mut self) {
         if self.active().display_type.overwrites() {
             self.draw_content(self.page_stack.len() - 1);
@@ -183,24 +1612,99 @@ impl Screen {
             self.draw_content(i);
         }
     }
-    //print the char, and get the char of each row, get the total row number
+    /*
+        The selection on the text page as positions on screen, with the start first. While
+        a prompt is open on top of the text, the cursor and anchor are the ones saved
+        for the text page when the prompt was opened.
+    */
+    fn visible_selection(&self) -> Option<(InsertionPoint, InsertionPoint)> {
+        let text_page = &self.page_stack[0];
+        let (anchor, ip) = if self.page_stack.len() == 1 {
+            (self.key_handler.anchor.as_ref()?, &self.key_handler.ip)
+        } else {
+            (
+                text_page.active_anchor.as_ref()?,
+                text_page.active_cursor_location.as_ref()?,
+            )
+        };
+        let (start, end) = if (anchor.y, anchor.x) < (ip.y, ip.x) {
+            (anchor, ip)
+        } else {
+            (ip, anchor)
+        };
+        let (rows, columns) = (self.key_handler.row_offset, self.key_handler.column_offset);
+        if (start.y, start.x) == (end.y, end.x) || end.y < rows {
+            return None;
+        }
+        let start = if start.y < rows {
+            InsertionPoint::new()
+        } else {
+            InsertionPoint {
+                x: start.x.saturating_sub(columns),
+                y: start.y - rows,
+            }
+        };
+        let end = InsertionPoint {
+            x: end.x.saturating_sub(columns),
+            y: end.y - rows,
+        };
+        Some((start, end))
+    }
+
+    /*
+        The rows of the text page that show their sensitive values: the row with the
+        cursor on it and any the selection is on, since what is selected is shown
+        highlighted column by column.
+    */
+    fn unmasked_rows(&self) -> Option<RangeInclusive<usize>> {
+        let text_page = &self.page_stack[0];
+        let (anchor, ip) = if self.page_stack.len() == 1 {
+            (self.key_handler.anchor.as_ref(), &self.key_handler.ip)
+        } else {
+            (
+                text_page.active_anchor.as_ref(),
+                text_page.active_cursor_location.as_ref()?,
+            )
+        };
+        let anchor = anchor.unwrap_or(ip);
+        Some(anchor.y.min(ip.y)..=anchor.y.max(ip.y))
+    }
+
+    //print the rows of the page that are on screen
     pub fn draw_content(&mut self, i: usize) {
+        let text = i == 0 && self.page_stack[0].display_type == PageType::Text;
+        let selection = if text { self.visible_selection() } else { None };
+        let masked = text && self.mask_values && self.hex.is_none();
+        let unmasked_rows = self.unmasked_rows();
         let on_screen = self.page_stack.get_mut(i).unwrap();
-        on_screen.row_contents = split_with_n(&on_screen.contents);
-        self.key_handler.num_of_rows = on_screen.row_contents.len();
-        let mut width: Vec<usize> = Vec::new();
-        let mut bytes: Vec<usize> = Vec::new();
-        for i in &on_screen.row_contents {
-            bytes.push(i.len());
-            width.push(i.width());
-        }
         let mut content = String::new();
+        let mut placeholders = Vec::new();
+        // Only the rows on screen are taken out of the buffer
         for i in 0..self.key_handler.screen_rows {
             let row_in_content = i + self.key_handler.row_offset;
-            if row_in_content < self.key_handler.num_of_rows {
+            if on_screen.contents.has_line(row_in_content) {
                 let mut offset_string = String::from("");
-                let row_contents = on_screen.row_contents.get(row_in_content).unwrap();
-                let (len, start) = if width[row_in_content] <= self.key_handler.column_offset {
+                let mut row_contents = on_screen.contents.line(row_in_content);
+                if masked
+                    && !unmasked_rows
+                        .as_ref()
+                        .is_some_and(|rows| rows.contains(&row_in_content))
+                {
+                    if let Some(m) = masking::mask_line(&row_contents) {
+                        row_contents = m;
+                    }
+                }
+                // Hidden and look-alike characters are shown as placeholders, and laid out as them
+                let (shown, columns) = hidden_chars::display(&row_contents);
+                row_contents = shown;
+                for c in columns {
+                    if c.end > self.key_handler.column_offset {
+                        let start = c.start.saturating_sub(self.key_handler.column_offset);
+                        placeholders.push((i, start..c.end - self.key_handler.column_offset));
+                    }
+                }
+                let width = row_contents.width();
+                let (len, start) = if width <= self.key_handler.column_offset {
                     (0, 0)
                 } else {
                     let (mut st, mut w) =
@@ -212,7 +1716,7 @@ impl Screen {
                         st = unicode_temp.0;
                         w = unicode_temp.1;
                     }
-                    if width[row_in_content] - w <= self.key_handler.screen_cols {
+                    if width - w <= self.key_handler.screen_cols {
                         (row_contents.len() - st.len(), st.len())
                     } else {
                         let (s_temp, _) = row_contents.unicode_truncate(
@@ -222,24 +1726,17 @@ impl Screen {
                     }
                 };
                 content.push_str(&offset_string);
-                if i < self.key_handler.screen_rows - 1 {
-                    if start + len == bytes[row_in_content] {
-                        content.push_str(&row_contents[start..start + len].replace('\n', "\r\n"));
-                    } else {
-                        content.push_str(&row_contents[start..start + len]);
-                        content.push_str("\r\n");
-                    };
-                } else {
-                    if start + len == bytes[row_in_content] {
-                        content.push_str(&row_contents[start..start + len].replace('\n', ""));
-                    } else {
-                        content.push_str(&row_contents[start..start + len]);
-                    };
+                content.push_str(&row_contents[start..start + len]);
+                if i < self.key_handler.screen_rows - 1
+                    && on_screen.contents.has_line(row_in_content + 1)
+                {
+                    content.push_str("\r\n");
                 }
             }
         }
-        self.key_handler.bytes_in_row = bytes;
-        self.key_handler.width_in_row = width;
+        if on_screen.display_type.masks_input() {
+            content = "*".repeat(content.width());
+        }
         let temp01 = match &self.mode {
             Mode::Normal => None,
             Mode::Find(t) => Some(String::from(t.as_str())),
@@ -292,6 +1789,8 @@ impl Screen {
         }
         let text: &str = &content.clone()[..];
         self.color_struct.set_find(temp01);
+        self.color_struct.set_selection(selection);
+        self.color_struct.set_placeholders(placeholders);
         self.color_struct.coloring(text);
 
         if !on_screen.display_type.overwrites() {
@@ -365,42 +1864,22 @@ impl Screen {
 
         if self.page_stack[i].display_type == PageType::Command {
             let x = self.key_handler.screen_cols / 4;
-            Screen::create_line(
-                &mut stdout,
-                self.key_handler.screen_cols / 2,
-                x,
-                y + 2,
-                "Toggle Highlight".to_owned(),
-            );
-            Screen::create_line(
-                &mut stdout,
-                self.key_handler.screen_cols / 2,
-                x,
-                y + 3,
-                "Find".to_owned(),
-            );
-            Screen::create_line(
-                &mut stdout,
-                self.key_handler.screen_cols / 2,
-                x,
-                y + 4,
-                "Replace".to_owned(),
-            );
-            Screen::create_line(
+            for (n, command) in COMMANDS.iter().enumerate() {
+                Screen::create_line(
+                    &mut stdout,
+                    self.key_handler.screen_cols / 2,
+                    x,
+                    y + 2 + n,
+                    command.to_string(),
+                );
+            }
+            Screen::print_at_times(
                 &mut stdout,
-                self.key_handler.screen_cols / 2,
                 x,
-                y + 5,
-                "File Info".to_owned(),
-            );
-            Screen::create_line(
-                &mut stdout,
+                y + 2 + COMMANDS.len(),
+                "-",
                 self.key_handler.screen_cols / 2,
-                x,
-                y + 6,
-                "Save As".to_owned(),
             );
-            Screen::print_at_times(&mut stdout, x, y + 7, "-", self.key_handler.screen_cols / 2);
         }
     }
 
@@ -481,6 +1960,15 @@ impl Screen {
     }
 }
 
+// Reads SFE_IDLE_LOCK_MINUTES, where 0 turns the idle lock off
+fn idle_lock_from_env() -> Option<Duration> {
+    let minutes = std::env::var("SFE_IDLE_LOCK_MINUTES")
+        .ok()
+        .and_then(|m| m.trim().parse::<f64>().ok())
+        .unwrap_or(DEFAULT_IDLE_LOCK_MINUTES);
+    (minutes.is_finite() && minutes > 0.0).then(|| Duration::from_secs_f64(minutes * 60.0))
+}
+
 // Potential additions to screen
 // pub pub fn active_type(&self) -> PageType {
 pub struct ColorWord {
@@ -492,7 +1980,19 @@ pub struct ColorWord {
     base_colors: Vec<Color>,
     parenthesis: usize,
     brackets: usize,
+    selection: Option<(InsertionPoint, InsertionPoint)>,
+    secrets: vault::Markers,
+    placeholders: Vec<(usize, Range<usize>)>,
+}
+
+// How a character is set apart from the others when it is printed
+#[derive(Clone, Copy, PartialEq, Default)]
+struct Marks {
+    selected: bool,
+    secret: bool,
+    placeholder: bool,
 }
+
 impl ColorWord {
     pub fn new(word: Option<String>, language: Language) -> Self {
         Self {
@@ -511,6 +2011,9 @@ impl ColorWord {
             ],
             parenthesis: 0,
             brackets: 0,
+            selection: None,
+            secrets: vault::Markers::default(),
+            placeholders: Vec::new(),
         }
     }
 
@@ -585,6 +2088,84 @@ impl ColorWord {
     pub fn set_find(&mut self, find: Option<String>) {
         self.word = find;
     }
+
+    // The selected text as the start and end positions in the text given to coloring
+    pub fn set_selection(&mut self, selection: Option<(InsertionPoint, InsertionPoint)>) {
+        self.selection = selection;
+    }
+
+    // The columns of each row of the text given to coloring that hold placeholders for hidden characters
+    pub fn set_placeholders(&mut self, placeholders: Vec<(usize, Range<usize>)>) {
+        self.placeholders = placeholders;
+    }
+
+    fn is_placeholder(&self, row: usize, column: usize) -> bool {
+        self.placeholders
+            .iter()
+            .any(|(r, columns)| *r == row && columns.contains(&column))
+    }
+
+    fn is_selected(&self, row: usize, column: usize) -> bool {
+        match &self.selection {
+            Some((start, end)) => {
+                (row, column) >= (start.y, start.x) && (row, column) < (end.y, end.x)
+            }
+            None => false,
+        }
+    }
+
+    /*
+        Prints a word in the given style, starting at a column of a row, with the
+        characters of it that are selected on a grey background, decrypted values, ⟦ and
+        ⟧ included, in magenta and placeholders for hidden characters in black on
+        yellow. The column is moved on past the word.
+    */
+    fn print_word(
+        &mut self,
+        stdout: &mut Stdout,
+        word: &str,
+        style: ContentStyle,
+        row: usize,
+        column: &mut usize,
+    ) {
+        let mut piece = String::new();
+        let mut piece_marks = Marks::default();
+        for c in word.chars() {
+            // A marker is shown as part of the value it starts or ends
+            let was_inside = self.secrets.inside;
+            self.secrets.next(c);
+            let marks = Marks {
+                selected: self.is_selected(row, *column),
+                secret: was_inside || self.secrets.inside,
+                placeholder: self.is_placeholder(row, *column),
+            };
+            if marks != piece_marks && !piece.is_empty() {
+                self.print_piece(stdout, &piece, style, piece_marks);
+                piece.clear();
+            }
+            piece_marks = marks;
+            piece.push(c);
+            *column += c.width().unwrap_or(0);
+        }
+        if !piece.is_empty() {
+            self.print_piece(stdout, &piece, style, piece_marks);
+        }
+    }
+
+    fn print_piece(&self, stdout: &mut Stdout, piece: &str, mut style: ContentStyle, marks: Marks) {
+        if marks.secret {
+            style.foreground_color = Some(Color::Magenta);
+        }
+        if marks.placeholder {
+            style.foreground_color = Some(Color::Black);
+            style.background_color = Some(Color::Yellow);
+        }
+        if marks.selected {
+            style.background_color = Some(Color::DarkGrey);
+        }
+        let _ = stdout.queue(style::PrintStyledContent(StyledContent::new(style, piece)));
+    }
+
     // Highlights the word which is currently being searched for (if there is one)
     pub fn get_background_color(&self, c: &str) -> Color {
         match &self.word {
@@ -602,12 +2183,14 @@ impl ColorWord {
     pub fn coloring(&mut self, text: &str) {
         self.parenthesis = 0;
         self.brackets = 0;
+        self.secrets = vault::Markers::default();
         let mut stdout = stdout();
         let line: Vec<&str> = text.split("\r\n").collect();
         let mut multi_line_comment: bool = false;
-        for i in 0..line.len() {
-            let words: Vec<String> = split_up(line[i].to_owned());
+        for row in 0..line.len() {
+            let words: Vec<String> = split_up(line[row].to_owned());
             let mut comment: bool = false;
+            let mut column = 0;
             for i in 0..words.len() {
                 if words[i] == self.language.comment_keyword {
                     comment = true;
@@ -631,39 +2214,39 @@ impl ColorWord {
                     }
                 });
                 if self.word == None {
-                    match stdout.queue(style::PrintStyledContent(StyledContent::new(
+                    self.print_word(
+                        &mut stdout,
+                        &words[i],
                         ContentStyle {
                             foreground_color: foreground_color,
                             background_color: Some(self.get_background_color(words[i].as_str())),
                             attributes: Attributes::default(),
                         },
-                        &words[i],
-                    ))) {
-                        Ok(_) => {}
-                        Err(_) => {}
-                    };
+                        row,
+                        &mut column,
+                    );
                 } else {
                     let temp01 =
                         pop_off(vec![words[i].clone()], self.word.clone().unwrap().as_str());
                     for w in temp01 {
-                        match stdout.queue(style::PrintStyledContent(StyledContent::new(
+                        self.print_word(
+                            &mut stdout,
+                            &w,
                             ContentStyle {
                                 foreground_color: foreground_color,
                                 background_color: Some(self.get_background_color(w.as_str())),
                                 attributes: Attributes::default(),
                             },
-                            &w,
-                        ))) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        };
+                            row,
+                            &mut column,
+                        );
                     }
                 }
                 if words[i] == self.language.ml_comment_end_keyword {
                     multi_line_comment = false;
                 }
             }
-            if i != line.len() - 1 {
+            if row != line.len() - 1 {
                 match stdout.queue(style::PrintStyledContent("\r\n".reset())) {
                     Ok(_) => {}
                     Err(_) => {}
@@ -721,3 +2304,49 @@ fn pop_off(input: Vec<String>, item: &str) -> Vec<String> {
     }
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A screen with a text page on it and no terminal behind it
+    fn screen(text: &str) -> Screen {
+        let mut screen = Screen::new(None, String::new());
+        screen.push(Page::new_with_contents(PageType::Text, String::from(text)));
+        screen
+    }
+
+    #[test]
+    fn idle_lock_needs_the_right_passphrase() {
+        let mut screen = screen("secret text");
+        // A plain file has no passphrase to unlock it with
+        assert!(!screen.can_lock());
+        screen.encryption = Some(EncryptionKey::new("right").unwrap());
+        assert!(screen.can_lock());
+        screen.lock();
+        assert!(screen.locked());
+        assert!(!screen.can_lock());
+        for wrong in ["wrong", "", "right "] {
+            screen.active_mut().set_contents(String::from(wrong));
+            screen.unlock();
+            assert!(screen.locked());
+            // What was typed is not kept around for the next try
+            assert_eq!(screen.active().contents.to_string(), "");
+        }
+        screen.active_mut().set_contents(String::from("right"));
+        screen.unlock();
+        assert!(!screen.locked());
+        assert_eq!(screen.text_page().contents.to_string(), "secret text");
+    }
+
+    #[test]
+    fn quitting_while_locked_discards_the_text() {
+        let mut screen = screen("secret text");
+        screen.encryption = Some(EncryptionKey::new("right").unwrap());
+        screen.lock();
+        screen.discard();
+        assert_eq!(screen.text_page().contents.to_string(), "");
+        assert!(screen.encryption.is_none());
+        assert!(!screen.can_lock());
+    }
+}
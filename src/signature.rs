@@ -0,0 +1,222 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use zeroize::Zeroize;
+
+/*
+    A detached signature is kept next to the file it signs, in "<file>.sig", as one line:
+
+        ed25519 <public key> <signature>
+
+    with both in base64. It covers the bytes of the file exactly as they are on disk,
+    so encrypted files can be checked without their passphrase.
+*/
+pub const SIGNATURE_EXTENSION: &str = "sig";
+const ALGORITHM: &str = "ed25519";
+
+// Public keys whose signatures are trusted, one "<public key> <name>" per line, in the config directory
+pub const TRUSTED_KEYS_FILE: &str = "trusted_keys";
+
+// The user's own key for signing files on save, kept as its base64 seed in the config directory
+pub const SIGNING_KEY_FILE: &str = "signing_key";
+
+// What is known about who wrote a file
+#[derive(PartialEq)]
+pub enum SignatureStatus {
+    Unsigned,
+    // Signed by the trusted key with this name
+    Verified(String),
+    // Signed, but by a key that is not trusted
+    Unverified,
+    // The file or its signature changed after it was signed
+    Invalid,
+}
+
+impl SignatureStatus {
+    // The badge shown on the text page, if the file has a signature
+    pub fn badge(&self) -> Option<String> {
+        match self {
+            SignatureStatus::Unsigned => None,
+            SignatureStatus::Verified(name) => Some(format!("signature verified: {}", name)),
+            SignatureStatus::Unverified => Some(String::from("unverified signature")),
+            SignatureStatus::Invalid => Some(String::from("INVALID SIGNATURE")),
+        }
+    }
+}
+
+pub struct TrustedKey {
+    key: VerifyingKey,
+    name: String,
+}
+
+// Where the signature of a file is kept
+pub fn signature_path(pathname: &str) -> String {
+    format!("{}.{}", pathname, SIGNATURE_EXTENSION)
+}
+
+/*
+    Checks that a signature was made over exactly this data. The key it names is
+    taken from the signature itself, so the check can tell a changed file apart
+    from one signed by someone who is not trusted.
+*/
+pub fn check(data: &[u8], signature_line: &str) -> Result<VerifyingKey, SignatureStatus> {
+    let (key, signature) = match parse_signature(signature_line) {
+        Some(t) => t,
+        None => return Err(SignatureStatus::Invalid),
+    };
+    match key.verify_strict(data, &signature) {
+        Ok(_) => Ok(key),
+        Err(_) => Err(SignatureStatus::Invalid),
+    }
+}
+
+// The status of a signature that matched its data, made with the given key
+pub fn trust(key: &VerifyingKey, trusted: &[TrustedKey]) -> SignatureStatus {
+    match trusted.iter().find(|t| t.key == *key) {
+        Some(t) => SignatureStatus::Verified(t.name.clone()),
+        None => SignatureStatus::Unverified,
+    }
+}
+
+// The signature line for the data, ending with a newline
+pub fn sign(data: &[u8], key: &SigningKey) -> String {
+    format!(
+        "{} {} {}\n",
+        ALGORITHM,
+        public_key_text(key),
+        STANDARD.encode(key.sign(data).to_bytes())
+    )
+}
+
+// The public half of a signing key, as it is written in a trusted keys file
+pub fn public_key_text(key: &SigningKey) -> String {
+    STANDARD.encode(key.verifying_key().as_bytes())
+}
+
+/*
+    Reads a trusted keys file, returning the number of the first line that is not
+    "<public key> <name>" if there is one. Blank lines and lines starting with '#'
+    are skipped, and a key without a name is shown as its first characters.
+*/
+pub fn parse_trusted_keys(text: &str) -> Result<Vec<TrustedKey>, usize> {
+    let mut keys = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key_text, name) = match line.split_once(char::is_whitespace) {
+            Some((k, name)) => (k, name.trim().to_owned()),
+            None => (line, line.chars().take(8).collect()),
+        };
+        match decode_key(key_text) {
+            Some(key) => keys.push(TrustedKey { key, name }),
+            None => return Err(n + 1),
+        }
+    }
+    Ok(keys)
+}
+
+// Reads the seed written by new_signing_key, wiping the text it was read from
+pub fn parse_signing_key(mut text: String) -> Option<SigningKey> {
+    let seed: Option<[u8; 32]> = STANDARD.decode(text.trim()).ok().and_then(|mut bytes| {
+        let seed = bytes.as_slice().try_into().ok();
+        bytes.zeroize();
+        seed
+    });
+    text.zeroize();
+    seed.map(|mut seed| {
+        let key = SigningKey::from_bytes(&seed);
+        seed.zeroize();
+        key
+    })
+}
+
+// A fresh signing key and the text to keep it in
+pub fn new_signing_key() -> (SigningKey, String) {
+    let mut seed = [0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+    let key = SigningKey::from_bytes(&seed);
+    let text = format!("{}\n", STANDARD.encode(seed));
+    seed.zeroize();
+    (key, text)
+}
+
+fn parse_signature(line: &str) -> Option<(VerifyingKey, Signature)> {
+    let mut parts = line.split_whitespace();
+    if parts.next()? != ALGORITHM {
+        return None;
+    }
+    let key = decode_key(parts.next()?)?;
+    let bytes: [u8; 64] = STANDARD.decode(parts.next()?).ok()?.try_into().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((key, Signature::from_bytes(&bytes)))
+}
+
+fn decode_key(text: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = STANDARD.decode(text).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_checks_out_against_its_data() {
+        let (key, _) = new_signing_key();
+        let line = sign(b"file contents", &key);
+        assert!(line.starts_with("ed25519 "));
+        assert_eq!(
+            check(b"file contents", &line).ok(),
+            Some(key.verifying_key())
+        );
+    }
+
+    #[test]
+    fn changed_data_or_signature_is_invalid() {
+        let (key, _) = new_signing_key();
+        let line = sign(b"file contents", &key);
+        assert!(check(b"file contents!", &line) == Err(SignatureStatus::Invalid));
+        let (other, _) = new_signing_key();
+        let swapped = line.replace(&public_key_text(&key), &public_key_text(&other));
+        assert!(check(b"file contents", &swapped) == Err(SignatureStatus::Invalid));
+        assert!(check(b"file contents", "rsa abc def") == Err(SignatureStatus::Invalid));
+        assert!(check(b"file contents", "") == Err(SignatureStatus::Invalid));
+    }
+
+    #[test]
+    fn only_trusted_keys_verify() {
+        let (key, _) = new_signing_key();
+        let (other, _) = new_signing_key();
+        let trusted = parse_trusted_keys(&format!(
+            "# team keys\n\n{} Alice Example\n",
+            public_key_text(&key)
+        ))
+        .unwrap();
+        assert!(
+            trust(&key.verifying_key(), &trusted)
+                == SignatureStatus::Verified(String::from("Alice Example"))
+        );
+        assert!(trust(&other.verifying_key(), &trusted) == SignatureStatus::Unverified);
+    }
+
+    #[test]
+    fn bad_trusted_keys_report_their_line() {
+        let (key, _) = new_signing_key();
+        let text = format!("{} ok\nnot a key\n", public_key_text(&key));
+        assert_eq!(parse_trusted_keys(&text).err(), Some(2));
+    }
+
+    #[test]
+    fn signing_key_round_trips() {
+        let (key, text) = new_signing_key();
+        let read = parse_signing_key(text).unwrap();
+        assert_eq!(read.to_bytes(), key.to_bytes());
+        assert!(parse_signing_key(String::from("too short")).is_none());
+    }
+}
@@ -0,0 +1,660 @@
+use crate::hex_editor;
+use crate::hidden_chars;
+use crate::line_ending::LineEnding;
+use ropey::Rope;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+// Files at least this big are read a chunk at a time as they are needed
+pub const LAZY_LOAD_BYTES: u64 = 64 * 1024 * 1024;
+// How much of a lazily opened file is read at once
+const CHUNK_BYTES: usize = 64 * 1024;
+// Unedited chunks kept in memory after being read for display
+const CACHED_CHUNKS: usize = 64;
+
+// One change to a buffer: the text removed at a char index and the text put in its place
+#[derive(Clone, PartialEq, Debug)]
+pub struct Edit {
+    pub index: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+/*
+    A run of whole lines of the text. Chunks of a lazily opened file start out as a
+    byte range of the file that is read when it is first needed; once edited, the
+    chunk's rope is an overlay that replaces that range when the file is saved.
+*/
+struct Chunk {
+    text: Option<Rope>,
+    source: Option<Range<u64>>,
+    chars: usize,
+    newlines: usize,
+}
+
+impl Chunk {
+    fn from_rope(rope: Rope) -> Chunk {
+        Chunk {
+            chars: rope.len_chars(),
+            newlines: rope.len_lines() - 1,
+            text: Some(rope),
+            source: None,
+        }
+    }
+
+    // Brings the counts up to date after the rope was edited
+    fn edited(&mut self) {
+        if let Some(rope) = &self.text {
+            self.chars = rope.len_chars();
+            self.newlines = rope.len_lines() - 1;
+        }
+        self.source = None;
+    }
+}
+
+// The file a lazily opened buffer reads its unedited chunks from
+struct Source {
+    file: File,
+    len: u64,
+    indexed_to: u64,
+}
+
+/*
+    The chunks of a buffer and the file they come from. Every chunk but the last ends
+    with '\n', so a line is always inside one chunk and finding line y only needs
+    the line count of each chunk before it. The file is indexed a chunk at a time,
+    as far as the cursor or the screen has got.
+*/
+struct Chunks {
+    list: Vec<Chunk>,
+    source: Option<Source>,
+    damaged: bool,
+}
+
+impl Chunks {
+    // Indexes the next chunk of the file, returning false when all of it is indexed
+    fn index_next(&mut self) -> bool {
+        let source = match &mut self.source {
+            Some(s) if s.indexed_to < s.len => s,
+            _ => return false,
+        };
+        let start = source.indexed_to;
+        let mut data = Vec::with_capacity(CHUNK_BYTES);
+        // A chunk ends after the last line break read, so long lines take more than one read
+        let end = loop {
+            let read_from = start + data.len() as u64;
+            let mut block = vec![0; CHUNK_BYTES.min((source.len - read_from) as usize)];
+            let result = source
+                .file
+                .seek(SeekFrom::Start(read_from))
+                .and_then(|_| source.file.read_exact(&mut block));
+            if result.is_err() {
+                // Whatever cannot be read is left out, and the buffer can no longer be saved
+                source.len = start;
+                self.damaged = true;
+                return false;
+            }
+            let searched = data.len();
+            data.extend_from_slice(&block);
+            if start + data.len() as u64 == source.len {
+                break data.len();
+            }
+            if let Some(n) = data[searched..].iter().rposition(|&b| b == b'\n') {
+                break searched + n + 1;
+            }
+        };
+        data.truncate(end);
+        source.indexed_to = start + end as u64;
+        let chunk = match std::str::from_utf8(&data) {
+            Ok(text) => Chunk {
+                text: None,
+                source: Some(start..start + end as u64),
+                chars: text.chars().count(),
+                newlines: data.iter().filter(|&&b| b == b'\n').count(),
+            },
+            Err(_) => {
+                self.damaged = true;
+                Chunk::from_rope(Rope::from(String::from_utf8_lossy(&data).into_owned()))
+            }
+        };
+        self.list.push(chunk);
+        true
+    }
+
+    fn index_all(&mut self) {
+        while self.index_next() {}
+    }
+
+    // The chunk holding a char index and the offset into it; the end of the text is in the last chunk
+    fn locate_char(&mut self, index: usize) -> (usize, usize) {
+        let mut start = 0;
+        let mut i = 0;
+        loop {
+            let chars = self.list[i].chars;
+            if index < start + chars {
+                return (i, index - start);
+            }
+            if i + 1 == self.list.len() && !self.index_next() {
+                return (i, chars);
+            }
+            start += chars;
+            i += 1;
+        }
+    }
+
+    // The chunk holding line y, the line's number inside it and the char index the chunk starts at
+    fn locate_line(&mut self, y: usize) -> Option<(usize, usize, usize)> {
+        let mut first_line = 0;
+        let mut start = 0;
+        let mut i = 0;
+        loop {
+            let (chars, newlines) = (self.list[i].chars, self.list[i].newlines);
+            if y < first_line + newlines {
+                return Some((i, y - first_line, start));
+            }
+            if i + 1 == self.list.len() && !self.index_next() {
+                // The last line has no line break
+                return (y == first_line + newlines).then_some((i, y - first_line, start));
+            }
+            first_line += newlines;
+            start += chars;
+            i += 1;
+        }
+    }
+
+    // The text of a chunk, reading it from the file if it is not in memory
+    fn text(&mut self, i: usize) -> &mut Rope {
+        if self.list[i].text.is_none() {
+            self.evict_except(i);
+            let rope = self.read_chunk(i);
+            self.list[i].text = Some(rope);
+        }
+        self.list[i].text.as_mut().unwrap()
+    }
+
+    fn read_chunk(&mut self, i: usize) -> Rope {
+        let chunk = &self.list[i];
+        let range = chunk.source.clone().unwrap_or(0..0);
+        let mut data = vec![0; (range.end - range.start) as usize];
+        let result = match &mut self.source {
+            Some(s) => s
+                .file
+                .seek(SeekFrom::Start(range.start))
+                .and_then(|_| s.file.read_exact(&mut data)),
+            None => Ok(()),
+        };
+        match result.ok().and_then(|_| String::from_utf8(data).ok()) {
+            Some(text) => Rope::from(text),
+            None => {
+                // The file changed under the buffer; keep the lines where they were
+                self.damaged = true;
+                let filler =
+                    "?".repeat(chunk.chars - chunk.newlines) + &"\n".repeat(chunk.newlines);
+                Rope::from(filler)
+            }
+        }
+    }
+
+    // Drops unedited chunks read earlier once too many are in memory
+    fn evict_except(&mut self, keep: usize) {
+        let cached = self
+            .list
+            .iter()
+            .filter(|c| c.text.is_some() && c.source.is_some())
+            .count();
+        if cached < CACHED_CHUNKS {
+            return;
+        }
+        for (i, chunk) in self.list.iter_mut().enumerate() {
+            if i != keep && chunk.source.is_some() {
+                chunk.text = None;
+            }
+        }
+    }
+
+    // Appends the chunk after an edited chunk onto it
+    fn join_next(&mut self, i: usize) {
+        let next = self.text(i + 1).clone();
+        self.list.remove(i + 1);
+        self.list[i].text.as_mut().unwrap().append(next);
+        self.list[i].edited();
+    }
+
+    // Joins the following chunks onto an edited chunk until it ends with a whole line again
+    fn join_broken_line(&mut self, i: usize) {
+        loop {
+            let rope = self.list[i].text.as_ref().unwrap();
+            let chars = rope.len_chars();
+            if chars > 0 && rope.char(chars - 1) == '\n' {
+                return;
+            }
+            if i + 1 == self.list.len() && !self.index_next() {
+                return;
+            }
+            self.join_next(i);
+        }
+    }
+}
+
+/*
+    The text of a page, kept in ropes so inserting and deleting anywhere takes
+    O(log n) and any line can be found without scanning the text before it.
+    Positions are char indices into the whole text; the cursor's (x, y) is the
+    display width into line y, the same as KeyHandler's insertion point. Only
+    '\n' ends a line, which is all buffers ever contain.
+
+    Most buffers are a single rope. Very large files are opened lazily instead:
+    a line index is built as far into the file as has been looked at, chunks are
+    read again when they are shown, and edits stay in memory until saving.
+
+    Every insert and remove is also written to a journal of edits, which the
+    screen collects after each keypress to build the undo history.
+*/
+pub struct TextBuffer {
+    chunks: RefCell<Chunks>,
+    journal: Vec<Edit>,
+}
+
+impl Default for TextBuffer {
+    fn default() -> TextBuffer {
+        TextBuffer::new()
+    }
+}
+
+impl TextBuffer {
+    pub fn new() -> TextBuffer {
+        TextBuffer::from(Rope::new())
+    }
+
+    /*
+        Opens a file lazily. Only plain UTF-8 text with LF line endings is read this
+        way, judging by the start of the file, since anything else has to be decoded
+        as a whole; None means the file should be read the usual way.
+    */
+    pub fn open_lazily(mut file: File) -> io::Result<Option<TextBuffer>> {
+        let len = file.metadata()?.len();
+        let mut sample = vec![0; CHUNK_BYTES.min(len as usize)];
+        file.read_exact(&mut sample)?;
+        let sample = match std::str::from_utf8(&sample) {
+            Ok(text) => text,
+            // The sample may end part way through a character
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&sample[..e.valid_up_to()]).unwrap_or_default()
+            }
+            Err(_) => return Ok(None),
+        };
+        if sample.starts_with('\u{feff}')
+            || hex_editor::looks_binary(sample.as_bytes())
+            || LineEnding::detect(sample) != LineEnding::Lf
+        {
+            return Ok(None);
+        }
+        let mut chunks = Chunks {
+            list: Vec::new(),
+            source: Some(Source {
+                file,
+                len,
+                indexed_to: 0,
+            }),
+            damaged: false,
+        };
+        if !chunks.index_next() {
+            return Ok(None);
+        }
+        Ok(Some(TextBuffer {
+            chunks: RefCell::new(chunks),
+            journal: Vec::new(),
+        }))
+    }
+
+    /*
+        Points a lazily opened buffer at the file it was just saved as, which now
+        holds exactly its text. The chunks it had not read yet would otherwise be
+        read at offsets into contents that may have been overwritten.
+    */
+    pub fn rebase(&mut self, file: File) -> io::Result<()> {
+        let len = file.metadata()?.len();
+        let mut chunks = Chunks {
+            list: Vec::new(),
+            source: Some(Source {
+                file,
+                len,
+                indexed_to: 0,
+            }),
+            damaged: false,
+        };
+        if !chunks.index_next() {
+            chunks.list.push(Chunk::from_rope(Rope::new()));
+        }
+        self.chunks = RefCell::new(chunks);
+        Ok(())
+    }
+
+    // Whether the buffer is still reading from the file it was lazily opened from
+    pub fn is_lazy(&self) -> bool {
+        self.chunks.borrow().source.is_some()
+    }
+
+    // Whether part of a lazily opened file was unreadable or not UTF-8, so saving would lose it
+    pub fn is_damaged(&self) -> bool {
+        self.chunks.borrow().damaged
+    }
+
+    // Text ending in '\n' has an empty last line for the cursor to go to
+    pub fn len_lines(&self) -> usize {
+        let mut chunks = self.chunks.borrow_mut();
+        chunks.index_all();
+        chunks.list.iter().map(|c| c.newlines).sum::<usize>() + 1
+    }
+
+    pub fn len_chars(&self) -> usize {
+        let mut chunks = self.chunks.borrow_mut();
+        chunks.index_all();
+        chunks.list.iter().map(|c| c.chars).sum()
+    }
+
+    // Whether line y exists, without reading any further into a lazily opened file than that
+    pub fn has_line(&self, y: usize) -> bool {
+        self.chunks.borrow_mut().locate_line(y).is_some()
+    }
+
+    // Whether a char index is at the end of the text
+    pub fn is_end(&self, index: usize) -> bool {
+        let mut chunks = self.chunks.borrow_mut();
+        let (i, offset) = chunks.locate_char(index);
+        offset == chunks.list[i].chars
+    }
+
+    // Line y without its line break, or nothing past the last line
+    pub fn line(&self, y: usize) -> String {
+        let mut chunks = self.chunks.borrow_mut();
+        let (i, line_in_chunk, _) = match chunks.locate_line(y) {
+            Some(l) => l,
+            None => return String::new(),
+        };
+        let mut line = chunks.text(i).line(line_in_chunk).to_string();
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        line
+    }
+
+    // How many columns line y takes up on screen
+    pub fn line_width(&self, y: usize) -> usize {
+        hidden_chars::width(&self.line(y))
+    }
+
+    // The char index of the cursor at display column x of line y
+    pub fn char_index(&self, x: usize, y: usize) -> usize {
+        let line = self.line(y);
+        let (before, _) = hidden_chars::truncate(&line, x);
+        let mut chunks = self.chunks.borrow_mut();
+        match chunks.locate_line(y) {
+            Some((i, line_in_chunk, start)) => {
+                start + chunks.text(i).line_to_char(line_in_chunk) + before.chars().count()
+            }
+            None => {
+                chunks.index_all();
+                chunks.list.iter().map(|c| c.chars).sum()
+            }
+        }
+    }
+
+    // The cursor position (display column, line) of a char index
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        let mut chunks = self.chunks.borrow_mut();
+        let (i, offset) = chunks.locate_char(index);
+        let lines_before: usize = chunks.list[..i].iter().map(|c| c.newlines).sum();
+        let rope = chunks.text(i);
+        let y = rope.char_to_line(offset);
+        let start = rope.line_to_char(y);
+        let line = rope.line(y).to_string();
+        (
+            hidden_chars::prefix_width(&line, offset - start),
+            lines_before + y,
+        )
+    }
+
+    pub fn insert(&mut self, index: usize, text: &str) {
+        self.insert_text(index, text);
+        self.journal.push(Edit {
+            index,
+            removed: String::new(),
+            inserted: text.to_owned(),
+        });
+    }
+
+    // Removes a range of chars, returning the text that was there
+    pub fn remove(&mut self, range: Range<usize>) -> String {
+        let start = range.start;
+        let removed = self.remove_text(range);
+        if !removed.is_empty() {
+            self.journal.push(Edit {
+                index: start,
+                removed: removed.clone(),
+                inserted: String::new(),
+            });
+        }
+        removed
+    }
+
+    // A copy of a range of chars, which may cross chunks
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut chunks = self.chunks.borrow_mut();
+        // Index as far as the end of the range before working out which chunks it covers
+        chunks.locate_char(range.end);
+        let (mut i, mut start) = chunks.locate_char(range.start);
+        let mut text = String::new();
+        let mut left = range.len();
+        while left > 0 && i < chunks.list.len() {
+            let end = (start + left).min(chunks.list[i].chars);
+            text.extend(chunks.text(i).slice(start..end).chunks());
+            left -= end - start;
+            i += 1;
+            start = 0;
+        }
+        text
+    }
+
+    // The edits made since this was last called, oldest first
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.journal)
+    }
+
+    // Takes an edit back out of the text, without journaling it
+    pub fn revert(&mut self, edit: &Edit) {
+        self.remove_text(edit.index..edit.index + edit.inserted.chars().count());
+        self.insert_text(edit.index, &edit.removed);
+    }
+
+    // Makes an edit again after it was reverted, without journaling it
+    pub fn reapply(&mut self, edit: &Edit) {
+        self.remove_text(edit.index..edit.index + edit.removed.chars().count());
+        self.insert_text(edit.index, &edit.inserted);
+    }
+
+    fn insert_text(&mut self, index: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let chunks = self.chunks.get_mut();
+        let (i, offset) = chunks.locate_char(index);
+        chunks.text(i).insert(offset, text);
+        chunks.list[i].edited();
+        chunks.join_broken_line(i);
+    }
+
+    // Removes a range of chars, returning the text that was there
+    fn remove_text(&mut self, range: Range<usize>) -> String {
+        if range.is_empty() {
+            return String::new();
+        }
+        let chunks = self.chunks.get_mut();
+        // Index as far as the end of the range before working out which chunks it covers
+        chunks.locate_char(range.end);
+        let (i, start) = chunks.locate_char(range.start);
+        chunks.text(i);
+        chunks.list[i].edited();
+        let end = start + range.len();
+        while end > chunks.list[i].chars && i + 1 < chunks.list.len() {
+            chunks.join_next(i);
+        }
+        let end = end.min(chunks.list[i].chars);
+        let removed = chunks.text(i).slice(start..end).to_string();
+        chunks.text(i).remove(start..end);
+        chunks.list[i].edited();
+        chunks.join_broken_line(i);
+        removed
+    }
+
+    // Writes the whole text out, reading unedited chunks of a lazily opened file as it goes
+    pub fn write_to(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut chunks = self.chunks.borrow_mut();
+        chunks.index_all();
+        for i in 0..chunks.list.len() {
+            let read = match chunks.list[i].text {
+                Some(_) => None,
+                None => Some(chunks.read_chunk(i)),
+            };
+            let rope = read.as_ref().or(chunks.list[i].text.as_ref()).unwrap();
+            for piece in rope.chunks() {
+                output.write_all(piece.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Rope> for TextBuffer {
+    fn from(rope: Rope) -> TextBuffer {
+        TextBuffer {
+            chunks: RefCell::new(Chunks {
+                list: vec![Chunk::from_rope(rope)],
+                source: None,
+                damaged: false,
+            }),
+            journal: Vec::new(),
+        }
+    }
+}
+
+impl From<String> for TextBuffer {
+    fn from(text: String) -> TextBuffer {
+        TextBuffer::from(Rope::from(text))
+    }
+}
+
+impl From<&str> for TextBuffer {
+    fn from(text: &str) -> TextBuffer {
+        TextBuffer::from(Rope::from_str(text))
+    }
+}
+
+impl fmt::Display for TextBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut text = Vec::new();
+        // Writing to memory cannot fail
+        let _ = self.write_to(&mut text);
+        write!(f, "{}", String::from_utf8_lossy(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A file big enough to be split into several chunks, removed again when dropped
+    struct LargeFile(PathBuf);
+
+    impl LargeFile {
+        fn new(name: &str, lines: usize) -> (LargeFile, String) {
+            let path =
+                std::env::temp_dir().join(format!("sfe-{}-{}.txt", name, std::process::id()));
+            let text: String = (0..lines).map(|n| format!("line {}\n", n)).collect();
+            fs::write(&path, &text).unwrap();
+            (LargeFile(path), text)
+        }
+
+        fn open(&self) -> TextBuffer {
+            TextBuffer::open_lazily(File::open(&self.0).unwrap())
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    impl Drop for LargeFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut buffer = TextBuffer::from("hello\nworld");
+        buffer.insert(5, ", there");
+        assert_eq!(buffer.to_string(), "hello, there\nworld");
+        assert_eq!(buffer.remove(0..7), "hello, ");
+        assert_eq!(buffer.to_string(), "there\nworld");
+        assert_eq!(buffer.len_lines(), 2);
+        assert_eq!(buffer.line(1), "world");
+        assert_eq!(buffer.position(buffer.char_index(3, 1)), (3, 1));
+    }
+
+    #[test]
+    fn edits_are_journaled_and_can_be_undone() {
+        let mut buffer = TextBuffer::from("abc");
+        buffer.insert(3, "def");
+        buffer.remove(0..2);
+        let edits = buffer.take_edits();
+        assert_eq!(edits.len(), 2);
+        assert!(buffer.take_edits().is_empty());
+        for edit in edits.iter().rev() {
+            buffer.revert(edit);
+        }
+        assert_eq!(buffer.to_string(), "abc");
+        for edit in &edits {
+            buffer.reapply(edit);
+        }
+        assert_eq!(buffer.to_string(), "cdef");
+    }
+
+    #[test]
+    fn lazy_buffer_reads_lines_on_demand() {
+        let (file, text) = LargeFile::new("lazy-read", 20_000);
+        let buffer = file.open();
+        assert!(buffer.is_lazy());
+        assert_eq!(buffer.line(0), "line 0");
+        assert_eq!(buffer.chunks.borrow().list.len(), 1);
+        assert_eq!(buffer.line(19_999), "line 19999");
+        assert!(buffer.chunks.borrow().list.len() > 1);
+        assert_eq!(buffer.len_lines(), 20_001);
+        assert_eq!(buffer.to_string(), text);
+        assert!(!buffer.is_damaged());
+    }
+
+    #[test]
+    fn lazy_buffer_keeps_edits_across_chunks() {
+        let (file, text) = LargeFile::new("lazy-edit", 20_000);
+        let mut buffer = file.open();
+        let first_chunk = buffer.chunks.borrow().list[0].chars;
+        // Joins the first two chunks by taking out the line break between them
+        buffer.remove(first_chunk - 1..first_chunk);
+        buffer.insert(0, "start\n");
+        let end = buffer.len_chars();
+        buffer.insert(end, "end");
+        let mut expected = text.clone();
+        let byte = text.char_indices().nth(first_chunk - 1).unwrap().0;
+        expected.remove(byte);
+        let expected = format!("start\n{}end", expected);
+        assert_eq!(buffer.to_string(), expected);
+        let mut written = Vec::new();
+        buffer.write_to(&mut written).unwrap();
+        assert_eq!(written, expected.as_bytes());
+        assert_eq!(buffer.slice(0..12), "start\nline 0");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::editor_error::EditorError;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::fmt;
+
+/*
+    The character encoding of a file and whether it starts with a byte order mark.
+    Buffers are always edited as UTF-8; files are decoded when they are opened and
+    encoded back into the same encoding when they are saved.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextEncoding {
+    encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+impl Default for TextEncoding {
+    fn default() -> TextEncoding {
+        TextEncoding {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+impl TextEncoding {
+    /*
+        Works out the encoding of a file: a byte order mark wins, then UTF-16 is recognised
+        by its zero bytes, then valid UTF-8 is taken as UTF-8, and anything else is left to
+        a statistical guess. Guesses that cannot decode the file cleanly fall back to
+        windows-1252, which maps every byte and so always saves back unchanged.
+    */
+    pub fn detect(data: &[u8]) -> TextEncoding {
+        if let Some((encoding, _)) = Encoding::for_bom(data) {
+            return TextEncoding {
+                encoding,
+                bom: true,
+            };
+        }
+        if let Some(encoding) = guess_utf16(data) {
+            return TextEncoding {
+                encoding,
+                bom: false,
+            };
+        }
+        if std::str::from_utf8(data).is_ok() {
+            return TextEncoding::default();
+        }
+        let mut detector = EncodingDetector::new();
+        detector.feed(data, true);
+        let guess = detector.guess(None, false);
+        let encoding = match guess.decode_without_bom_handling_and_without_replacement(data) {
+            Some(_) => guess,
+            None => WINDOWS_1252,
+        };
+        TextEncoding {
+            encoding,
+            bom: false,
+        }
+    }
+
+    // Decodes a file's contents, detecting the encoding first
+    pub fn decode_file(data: &[u8]) -> (String, TextEncoding) {
+        let text_encoding = TextEncoding::detect(data);
+        (text_encoding.decode(data), text_encoding)
+    }
+
+    // Decodes data in this encoding, dropping the byte order mark if there is one
+    pub fn decode(&self, data: &[u8]) -> String {
+        let data = match Encoding::for_bom(data) {
+            Some((encoding, length)) if encoding == self.encoding => &data[length..],
+            _ => data,
+        };
+        self.encoding
+            .decode_without_bom_handling(data)
+            .0
+            .into_owned()
+    }
+
+    /*
+        Encodes the buffer for saving. Characters the encoding has no code for are an
+        error rather than being replaced, so saving never silently changes the text.
+    */
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, EditorError> {
+        let mut output = Vec::with_capacity(text.len() + 3);
+        if self.bom {
+            output.extend_from_slice(self.bom_bytes());
+        }
+        if self.encoding == UTF_16LE {
+            text.encode_utf16()
+                .for_each(|unit| output.extend_from_slice(&unit.to_le_bytes()));
+        } else if self.encoding == UTF_16BE {
+            text.encode_utf16()
+                .for_each(|unit| output.extend_from_slice(&unit.to_be_bytes()));
+        } else {
+            let (bytes, _, had_errors) = self.encoding.encode(text);
+            if had_errors {
+                return Err(EditorError::Unencodable(
+                    self.encoding.name().to_owned(),
+                    self.first_unencodable(text),
+                ));
+            }
+            output.extend_from_slice(&bytes);
+        }
+        Ok(output)
+    }
+
+    // Looks up an encoding by any of its usual names, such as "latin1" or "utf-16be"
+    pub fn from_label(label: &str) -> Option<TextEncoding> {
+        let encoding = Encoding::for_label(label.trim().as_bytes())?;
+        // UTF-16 is written by hand in encode; the "replacement" encoding cannot be written at all
+        if encoding.output_encoding() != encoding && encoding != UTF_16LE && encoding != UTF_16BE {
+            return None;
+        }
+        Some(TextEncoding {
+            encoding,
+            bom: false,
+        })
+    }
+
+    // Only the Unicode encodings have a byte order mark
+    pub fn supports_bom(&self) -> bool {
+        self.encoding == UTF_8 || self.encoding == UTF_16LE || self.encoding == UTF_16BE
+    }
+
+    pub fn is_utf16(&self) -> bool {
+        self.encoding == UTF_16LE || self.encoding == UTF_16BE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    fn bom_bytes(&self) -> &'static [u8] {
+        if self.encoding == UTF_16LE {
+            &[0xFF, 0xFE]
+        } else if self.encoding == UTF_16BE {
+            &[0xFE, 0xFF]
+        } else if self.encoding == UTF_8 {
+            &[0xEF, 0xBB, 0xBF]
+        } else {
+            &[]
+        }
+    }
+
+    fn first_unencodable(&self, text: &str) -> char {
+        let mut buffer = [0u8; 4];
+        text.chars()
+            .find(|c| self.encoding.encode(c.encode_utf8(&mut buffer)).2)
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bom {
+            write!(f, "{} BOM", self.encoding.name())
+        } else {
+            write!(f, "{}", self.encoding.name())
+        }
+    }
+}
+
+// Text in UTF-16 without a byte order mark has a zero in every other byte for ASCII characters
+fn guess_utf16(data: &[u8]) -> Option<&'static Encoding> {
+    if data.len() < 2 || !data.len().is_multiple_of(2) {
+        return None;
+    }
+    let pairs = data.len() / 2;
+    let even_zeros = data.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_zeros = data.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    let encoding = if odd_zeros * 2 > pairs && even_zeros * 10 < pairs {
+        UTF_16LE
+    } else if even_zeros * 2 > pairs && odd_zeros * 10 < pairs {
+        UTF_16BE
+    } else {
+        return None;
+    };
+    // Unpaired surrogates mean it was not UTF-16 after all
+    encoding
+        .decode_without_bom_handling_and_without_replacement(data)
+        .map(|_| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decoding a file and encoding it again gives back the same bytes
+    fn assert_round_trip(data: &[u8], name: &str, bom: bool) {
+        let (text, text_encoding) = TextEncoding::decode_file(data);
+        assert_eq!(text_encoding.name(), name);
+        assert_eq!(text_encoding.bom, bom);
+        assert_eq!(text_encoding.encode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn utf8_round_trips() {
+        assert_round_trip("plain ascii\n".as_bytes(), "UTF-8", false);
+        assert_round_trip("grüße, 日本語\n".as_bytes(), "UTF-8", false);
+        assert_round_trip("\u{FEFF}with a mark\n".as_bytes(), "UTF-8", true);
+    }
+
+    #[test]
+    fn utf16_round_trips() {
+        let le: Vec<u8> = "text\n".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let be: Vec<u8> = "text\n".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_round_trip(&le, "UTF-16LE", false);
+        assert_round_trip(&be, "UTF-16BE", false);
+        assert_round_trip(&[&[0xFF, 0xFE], &le[..]].concat(), "UTF-16LE", true);
+        assert_round_trip(&[&[0xFE, 0xFF], &be[..]].concat(), "UTF-16BE", true);
+    }
+
+    #[test]
+    fn latin1_round_trips() {
+        let data = b"caf\xe9 cr\xe8me br\xfbl\xe9e\n";
+        let (text, text_encoding) = TextEncoding::decode_file(data);
+        assert_eq!(text, "café crème brûlée\n");
+        assert_eq!(text_encoding.encode(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn unencodable_characters_are_refused() {
+        let latin1 = TextEncoding::from_label("latin1").unwrap();
+        assert!(matches!(
+            latin1.encode("price: 5€ or 5¥, ☃"),
+            Err(EditorError::Unencodable(_, '☃'))
+        ));
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            TextEncoding::from_label(" utf-16be ").unwrap().name(),
+            "UTF-16BE"
+        );
+        assert_eq!(
+            TextEncoding::from_label("latin1").unwrap().name(),
+            "windows-1252"
+        );
+        assert!(TextEncoding::from_label("replacement").is_none());
+        assert!(TextEncoding::from_label("no such encoding").is_none());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::insertion_point::InsertionPoint;
+use crate::text_buffer::{Edit, TextBuffer};
+
+// Steps beyond this many are forgotten, oldest first
+const UNDO_LIMIT: usize = 1000;
+// Start of a saved undo history, followed by a format version
+const MAGIC: &[u8; 7] = b"SFEUNDO";
+const VERSION: u8 = 1;
+
+// Runs of keypresses that are undone together
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EditGroup {
+    Typing,
+    Deleting,
+}
+
+// The edits undone by one press of Ctrl+Z, and where the cursor was before and after them
+pub struct UndoStep {
+    pub edits: Vec<Edit>,
+    pub cursor_before: InsertionPoint,
+    pub cursor_after: InsertionPoint,
+}
+
+/*
+    The undo and redo stacks of the text page. Consecutive typing, or consecutive
+    deleting, goes into a single step until the cursor is moved or something else
+    is done; everything else, such as a whole Replace, is a step of its own.
+*/
+pub struct UndoHistory {
+    undo: Vec<UndoStep>,
+    redo: Vec<UndoStep>,
+    open_group: Option<EditGroup>,
+}
+
+impl Default for UndoHistory {
+    fn default() -> UndoHistory {
+        UndoHistory::new()
+    }
+}
+
+impl UndoHistory {
+    pub fn new() -> UndoHistory {
+        UndoHistory {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            open_group: None,
+        }
+    }
+
+    // Adds the edits of one action, joining them onto the last step when they continue its group
+    pub fn record(
+        &mut self,
+        edits: Vec<Edit>,
+        cursor_before: InsertionPoint,
+        cursor_after: InsertionPoint,
+        group: Option<EditGroup>,
+    ) {
+        if edits.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        if group.is_some() && group == self.open_group {
+            if let Some(step) = self.undo.last_mut() {
+                step.edits.extend(edits);
+                step.cursor_after = cursor_after;
+                return;
+            }
+        }
+        self.undo.push(UndoStep {
+            edits,
+            cursor_before,
+            cursor_after,
+        });
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.remove(0);
+        }
+        self.open_group = group;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty() && self.redo.is_empty()
+    }
+
+    // The next edit starts a new step
+    pub fn break_group(&mut self) {
+        self.open_group = None;
+    }
+
+    // Reverts the last step, returning where the cursor goes
+    pub fn undo(&mut self, buffer: &mut TextBuffer) -> Option<InsertionPoint> {
+        let step = self.undo.pop()?;
+        for edit in step.edits.iter().rev() {
+            buffer.revert(edit);
+        }
+        let cursor = step.cursor_before.clone();
+        self.redo.push(step);
+        self.open_group = None;
+        Some(cursor)
+    }
+
+    // Makes the last undone step again, returning where the cursor goes
+    pub fn redo(&mut self, buffer: &mut TextBuffer) -> Option<InsertionPoint> {
+        let step = self.redo.pop()?;
+        for edit in &step.edits {
+            buffer.reapply(edit);
+        }
+        let cursor = step.cursor_after.clone();
+        self.undo.push(step);
+        self.open_group = None;
+        Some(cursor)
+    }
+}
+
+/*
+    Saved undo histories are a header, the hash of the file the history belongs to,
+    and then both stacks. Numbers are little endian u64s and text is its length
+    followed by its UTF-8 bytes.
+*/
+impl UndoHistory {
+    pub fn to_bytes(&self, content_hash: &[u8; 32]) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend_from_slice(MAGIC);
+        output.push(VERSION);
+        output.extend_from_slice(content_hash);
+        for stack in [&self.undo, &self.redo] {
+            write_number(&mut output, stack.len());
+            for step in stack {
+                for ip in [&step.cursor_before, &step.cursor_after] {
+                    write_number(&mut output, ip.x);
+                    write_number(&mut output, ip.y);
+                }
+                write_number(&mut output, step.edits.len());
+                for edit in &step.edits {
+                    write_number(&mut output, edit.index);
+                    write_text(&mut output, &edit.removed);
+                    write_text(&mut output, &edit.inserted);
+                }
+            }
+        }
+        output
+    }
+
+    // Reads a saved history back, or None if it is damaged or belongs to different file contents
+    pub fn from_bytes(data: &[u8], content_hash: &[u8; 32]) -> Option<UndoHistory> {
+        let mut reader = Reader { data };
+        if reader.take(MAGIC.len())? != MAGIC
+            || reader.take(1)? != [VERSION]
+            || reader.take(content_hash.len())? != content_hash
+        {
+            return None;
+        }
+        let undo = reader.steps()?;
+        let redo = reader.steps()?;
+        if !reader.data.is_empty() {
+            return None;
+        }
+        Some(UndoHistory {
+            undo,
+            redo,
+            open_group: None,
+        })
+    }
+}
+
+fn write_number(output: &mut Vec<u8>, number: usize) {
+    output.extend_from_slice(&(number as u64).to_le_bytes());
+}
+
+fn write_text(output: &mut Vec<u8>, text: &str) {
+    write_number(output, text.len());
+    output.extend_from_slice(text.as_bytes());
+}
+
+// Walks through a saved history, failing on anything cut short
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if length > self.data.len() {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let bytes = self.take(8)?.try_into().ok()?;
+        usize::try_from(u64::from_le_bytes(bytes)).ok()
+    }
+
+    fn text(&mut self) -> Option<String> {
+        let length = self.number()?;
+        String::from_utf8(self.take(length)?.to_vec()).ok()
+    }
+
+    fn cursor(&mut self) -> Option<InsertionPoint> {
+        let mut ip = InsertionPoint::new();
+        ip.x = self.number()?;
+        ip.y = self.number()?;
+        Some(ip)
+    }
+
+    fn steps(&mut self) -> Option<Vec<UndoStep>> {
+        let count = self.number()?;
+        // Counts come from the file, so nothing is reserved up front
+        let mut steps = Vec::new();
+        for _ in 0..count {
+            let cursor_before = self.cursor()?;
+            let cursor_after = self.cursor()?;
+            let mut edits = Vec::new();
+            for _ in 0..self.number()? {
+                edits.push(Edit {
+                    index: self.number()?,
+                    removed: self.text()?,
+                    inserted: self.text()?,
+                });
+            }
+            steps.push(UndoStep {
+                edits,
+                cursor_before,
+                cursor_after,
+            });
+        }
+        Some(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(x: usize) -> InsertionPoint {
+        let mut ip = InsertionPoint::new();
+        ip.x = x;
+        ip
+    }
+
+    // Makes one keypress worth of edits and records them the way the screen does
+    fn press(
+        history: &mut UndoHistory,
+        buffer: &mut TextBuffer,
+        edit: impl FnOnce(&mut TextBuffer),
+        group: Option<EditGroup>,
+    ) {
+        let before = buffer.len_chars();
+        edit(buffer);
+        let after = buffer.len_chars();
+        history.record(buffer.take_edits(), cursor(before), cursor(after), group);
+    }
+
+    fn type_text(history: &mut UndoHistory, buffer: &mut TextBuffer, text: &str) {
+        for c in text.chars() {
+            let end = buffer.len_chars();
+            press(
+                history,
+                buffer,
+                |b| b.insert(end, &c.to_string()),
+                Some(EditGroup::Typing),
+            );
+        }
+    }
+
+    fn backspace(history: &mut UndoHistory, buffer: &mut TextBuffer) {
+        let end = buffer.len_chars();
+        press(
+            history,
+            buffer,
+            |b| {
+                b.remove(end - 1..end);
+            },
+            Some(EditGroup::Deleting),
+        );
+    }
+
+    #[test]
+    fn typing_is_undone_in_one_step() {
+        let mut history = UndoHistory::new();
+        let mut buffer = TextBuffer::new();
+        type_text(&mut history, &mut buffer, "hello");
+        assert_eq!(history.undo(&mut buffer).unwrap().x, 0);
+        assert_eq!(buffer.to_string(), "");
+        assert!(history.undo(&mut buffer).is_none());
+        assert_eq!(history.redo(&mut buffer).unwrap().x, 5);
+        assert_eq!(buffer.to_string(), "hello");
+    }
+
+    #[test]
+    fn groups_end_when_the_kind_of_edit_changes_or_the_cursor_moves() {
+        let mut history = UndoHistory::new();
+        let mut buffer = TextBuffer::new();
+        type_text(&mut history, &mut buffer, "abc");
+        backspace(&mut history, &mut buffer);
+        backspace(&mut history, &mut buffer);
+        type_text(&mut history, &mut buffer, "xy");
+        history.break_group();
+        type_text(&mut history, &mut buffer, "z");
+        assert_eq!(buffer.to_string(), "axyz");
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "axy");
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "a");
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "abc");
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "");
+    }
+
+    #[test]
+    fn ungrouped_edits_are_steps_of_their_own() {
+        let mut history = UndoHistory::new();
+        let mut buffer = TextBuffer::from("one two");
+        press(&mut history, &mut buffer, |b| b.insert(7, "!"), None);
+        press(&mut history, &mut buffer, |b| b.insert(8, "?"), None);
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "one two!");
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut history = UndoHistory::new();
+        let mut buffer = TextBuffer::new();
+        type_text(&mut history, &mut buffer, "ab");
+        history.undo(&mut buffer);
+        type_text(&mut history, &mut buffer, "c");
+        assert!(history.redo(&mut buffer).is_none());
+        assert_eq!(buffer.to_string(), "c");
+    }
+
+    #[test]
+    fn saved_history_round_trips() {
+        let mut history = UndoHistory::new();
+        let mut buffer = TextBuffer::new();
+        type_text(&mut history, &mut buffer, "saved");
+        let hash = [7; 32];
+        let data = history.to_bytes(&hash);
+        assert!(UndoHistory::from_bytes(&data, &[0; 32]).is_none());
+        assert!(UndoHistory::from_bytes(&data[..data.len() - 1], &hash).is_none());
+        let mut restored = UndoHistory::from_bytes(&data, &hash).unwrap();
+        restored.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "");
+    }
+}
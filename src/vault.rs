@@ -0,0 +1,367 @@
+use crate::editor_error::EditorError;
+use crate::encryption::{self, EncryptionKey};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::iter;
+
+// Start and end an encrypted value in a file on disk; the value is between them in base64
+pub const TOKEN_PREFIX: &str = "$SFE-VAULT;1;";
+pub const TOKEN_END: char = ';';
+
+// Mark where a decrypted value starts and ends in the buffer
+pub const SECRET_START: char = '⟦';
+pub const SECRET_END: char = '⟧';
+
+/*
+    Values encrypted inside an otherwise readable file, like ansible-vault inline
+    values. On disk each one is a single-line token: TOKEN_PREFIX, the value
+    encrypted in the editor's own format (see encryption.rs) in base64, and ';'. In
+    the buffer it is shown decrypted between ⟦ and ⟧, and it is encrypted again
+    when the file is saved. A value that was not changed keeps the token it was
+    read from, so only the values that were edited show up in diffs.
+
+    A ⟦ or ⟧ that is part of the text has a '\' put before it in the buffer, and
+    the '\'s right before a marker are doubled, so that nothing the file holds
+    is taken for a value. The escapes are taken out again when the file is saved.
+*/
+pub struct Vault {
+    // New values are encrypted with the first; the others read tokens made with other salts
+    keys: Vec<EncryptionKey>,
+    /*
+        The values with the tokens they were read from or last written as. Each
+        token is used for one value only, so values that are the same do not end
+        up with the same token.
+    */
+    tokens: Vec<(String, String)>,
+}
+
+impl Vault {
+    pub fn new(key: EncryptionKey) -> Vault {
+        Vault {
+            keys: vec![key],
+            tokens: Vec::new(),
+        }
+    }
+
+    /*
+        Derives the keys for the tokens in the text from the passphrase and decrypts
+        them, returning the vault and the text with the values in place of the tokens.
+        A key is only derived once for each salt, which is normally one per file.
+    */
+    pub fn unlock(passphrase: &str, text: &str) -> Result<(Vault, String), EditorError> {
+        let mut keys: Vec<EncryptionKey> = Vec::new();
+        for (_, line, token) in tokens(text) {
+            let data = decode(line, token)?;
+            if !keys.iter().any(|k| k.fits(&data)) {
+                // Decrypting is what tells a wrong passphrase apart from a right one
+                let key = EncryptionKey::from_header(passphrase, &data)?;
+                key.decrypt(&data)?;
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            keys.push(EncryptionKey::new(passphrase)?);
+        }
+        let mut vault = Vault {
+            keys,
+            tokens: Vec::new(),
+        };
+        let revealed = vault.reveal(text)?;
+        Ok((vault, revealed))
+    }
+
+    // The key typed passphrases are checked against, for the idle lock
+    pub fn key(&self) -> &EncryptionKey {
+        &self.keys[0]
+    }
+
+    // The text with every token it has a key for replaced by its value between ⟦ and ⟧
+    pub fn reveal(&mut self, text: &str) -> Result<String, EditorError> {
+        let mut revealed = String::with_capacity(text.len());
+        let mut plain = String::new();
+        let mut copied = 0;
+        for (start, line, token) in tokens(text) {
+            plain.push_str(&text[copied..start]);
+            copied = start + token.len();
+            let data = decode(line, token)?;
+            let key = match self.keys.iter().find(|k| k.fits(&data)) {
+                Some(k) => k,
+                None => {
+                    plain.push_str(token);
+                    continue;
+                }
+            };
+            let value =
+                String::from_utf8(key.decrypt(&data)?).map_err(|_| EditorError::BadSecret(line))?;
+            push_escaped(&mut revealed, &plain, true);
+            plain.clear();
+            revealed.push(SECRET_START);
+            push_escaped(&mut revealed, &value, true);
+            revealed.push(SECRET_END);
+            if !self.tokens.iter().any(|(_, t)| t == token) {
+                self.tokens.push((value, token.to_owned()));
+            }
+        }
+        plain.push_str(&text[copied..]);
+        push_escaped(&mut revealed, &plain, false);
+        Ok(revealed)
+    }
+
+    // The text as it is written to disk, with every value between ⟦ and ⟧ encrypted
+    pub fn seal(&mut self, text: &str) -> Result<String, EditorError> {
+        let pieces = split(text)?;
+        let mut unused = std::mem::take(&mut self.tokens);
+        let mut sealed = String::with_capacity(text.len());
+        for (secret, piece) in pieces {
+            if !secret {
+                sealed.push_str(&piece);
+                continue;
+            }
+            let token = match unused.iter().position(|(value, _)| *value == piece) {
+                Some(i) => unused.remove(i).1,
+                None => {
+                    let data = self.keys[0].encrypt(piece.as_bytes());
+                    format!("{}{}{}", TOKEN_PREFIX, STANDARD.encode(data), TOKEN_END)
+                }
+            };
+            sealed.push_str(&token);
+            self.tokens.push((piece, token));
+        }
+        Ok(sealed)
+    }
+}
+
+/*
+    Follows a buffer a character at a time to tell which of its characters are
+    inside a value. A ⟦ or ⟧ after an odd number of '\'s is part of the text, as
+    is a ⟦ inside a value or a ⟧ outside one.
+*/
+#[derive(Default)]
+pub struct Markers {
+    pub inside: bool,
+    backslashes: usize,
+}
+
+impl Markers {
+    // Takes the next character, returning whether it starts or ends a value
+    pub fn next(&mut self, c: char) -> bool {
+        let escaped = self.backslashes % 2 == 1;
+        self.backslashes = if c == '\\' { self.backslashes + 1 } else { 0 };
+        let marker = !escaped
+            && match c {
+                SECRET_START => !self.inside,
+                SECRET_END => self.inside,
+                _ => false,
+            };
+        if marker {
+            self.inside = !self.inside;
+        }
+        marker
+    }
+}
+
+// Text as it is shown in a buffer with decrypted values, with its own ⟦s and ⟧s escaped
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    push_escaped(&mut escaped, text, false);
+    escaped
+}
+
+// Whether the text has a ⟦ or ⟧ that would be taken for the start or end of a value
+pub fn has_markers(text: &str) -> bool {
+    let mut backslashes = 0;
+    for c in text.chars() {
+        if (c == SECRET_START || c == SECRET_END) && backslashes % 2 == 0 {
+            return true;
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    }
+    false
+}
+
+/*
+    Adds text to the buffer so that split reads it back unchanged: each ⟦ and ⟧
+    gets a '\' before it, and the '\'s before it are doubled. So are the '\'s at
+    the end, when a marker is put after the text.
+*/
+fn push_escaped(buffer: &mut String, text: &str, marker_follows: bool) {
+    let mut backslashes = 0;
+    for c in text.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            SECRET_START | SECRET_END => {
+                buffer.extend(iter::repeat_n('\\', backslashes * 2 + 1));
+            }
+            _ => buffer.extend(iter::repeat_n('\\', backslashes)),
+        }
+        backslashes = 0;
+        buffer.push(c);
+    }
+    let ending = if marker_follows { 2 } else { 1 };
+    buffer.extend(iter::repeat_n('\\', backslashes * ending));
+}
+
+/*
+    Splits a buffer into the text outside values and the values, in order, each
+    with its escapes taken out and marked with whether it is a value.
+*/
+fn split(text: &str) -> Result<Vec<(bool, String)>, EditorError> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut markers = Markers::default();
+    let mut backslashes = 0;
+    let mut line = 1;
+    let mut start_line = 1;
+    for c in text.chars() {
+        let marker = markers.next(c);
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            SECRET_START | SECRET_END => piece.extend(iter::repeat_n('\\', backslashes / 2)),
+            _ => piece.extend(iter::repeat_n('\\', backslashes)),
+        }
+        backslashes = 0;
+        if c == '\n' {
+            line += 1;
+        }
+        if marker {
+            pieces.push((!markers.inside, std::mem::take(&mut piece)));
+            start_line = line;
+        } else {
+            piece.push(c);
+        }
+    }
+    if markers.inside {
+        return Err(EditorError::UnclosedSecret(start_line));
+    }
+    piece.extend(iter::repeat_n('\\', backslashes));
+    pieces.push((false, piece));
+    Ok(pieces)
+}
+
+// Whether the text has encrypted values that have not been decrypted
+pub fn has_tokens(text: &str) -> bool {
+    text.contains(TOKEN_PREFIX)
+}
+
+// The text with the values between ⟦ and ⟧ blanked out, line for line, for the secret scanner
+pub fn hide_secrets(text: &str) -> String {
+    let mut markers = Markers::default();
+    text.chars()
+        .map(|c| match markers.next(c) {
+            false if markers.inside && c != '\n' => '*',
+            _ => c,
+        })
+        .collect()
+}
+
+// The text with its tokens blanked out, for the secret scanner; they are encrypted already
+pub fn hide_tokens(text: &str) -> String {
+    let mut hidden = text.to_owned();
+    for (start, _, token) in tokens(text) {
+        hidden.replace_range(start..start + token.len(), &"*".repeat(token.len()));
+    }
+    hidden
+}
+
+/*
+    Every token in the text with its byte offset and the line it is on, counting
+    from 1. A token missing its ';' runs to the end of its base64, and fails to
+    decode rather than taking in the text after it.
+*/
+fn tokens(text: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    text.match_indices(TOKEN_PREFIX).map(move |(start, _)| {
+        let body = &text[start + TOKEN_PREFIX.len()..];
+        let mut end = body
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '='))
+            .unwrap_or(body.len());
+        if body[end..].starts_with(TOKEN_END) {
+            end += TOKEN_END.len_utf8();
+        }
+        let line = text[..start].matches('\n').count() + 1;
+        (start, line, &text[start..start + TOKEN_PREFIX.len() + end])
+    })
+}
+
+// The encrypted value in a token
+fn decode(line: usize, token: &str) -> Result<Vec<u8>, EditorError> {
+    let body = match token[TOKEN_PREFIX.len()..].strip_suffix(TOKEN_END) {
+        Some(b) => b,
+        None => return Err(EditorError::BadSecret(line)),
+    };
+    match STANDARD.decode(body) {
+        Ok(data) if encryption::is_encrypted(&data) => Ok(data),
+        _ => Err(EditorError::BadSecret(line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seals the buffer and opens the sealed text again with the same passphrase
+    fn round_trip(buffer: &str) -> (String, String) {
+        let mut vault = Vault::new(EncryptionKey::new("passphrase").unwrap());
+        let sealed = vault.seal(buffer).unwrap();
+        let (_, revealed) = Vault::unlock("passphrase", &sealed).unwrap();
+        (sealed, revealed)
+    }
+
+    #[test]
+    fn values_are_encrypted_and_decrypted() {
+        let (sealed, revealed) = round_trip("user: me\npassword: ⟦hunter2⟧\n");
+        assert!(sealed.starts_with("user: me\npassword: $SFE-VAULT;1;"));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(revealed, "user: me\npassword: ⟦hunter2⟧\n");
+    }
+
+    #[test]
+    fn markers_in_the_text_are_escaped() {
+        // A file's own ⟦s and ⟧s come back unchanged when it is opened and saved
+        let text = "[⟦not a value⟧] ⟦ alone, \\⟧ and C:\\";
+        let mut vault = Vault::new(EncryptionKey::new("passphrase").unwrap());
+        let revealed = vault.reveal(text).unwrap();
+        assert_eq!(revealed, escape(text));
+        assert!(!has_markers(&revealed));
+        assert_eq!(vault.seal(&revealed).unwrap(), text);
+
+        // Values may hold markers and end in '\'s
+        let (sealed, revealed) = round_trip("⟦a\\⟧b\\\\\\\\⟧ ⟦c⟧\\\\⟦d⟧");
+        assert_eq!(revealed, "⟦a\\⟧b\\\\\\\\⟧ ⟦c⟧\\\\⟦d⟧");
+        let pieces = split(&revealed).unwrap();
+        assert_eq!(pieces[1], (true, String::from("a⟧b\\\\")));
+        assert_eq!(pieces[4], (false, String::from("\\")));
+        assert_eq!(sealed.matches(TOKEN_PREFIX).count(), 3);
+    }
+
+    #[test]
+    fn equal_values_get_their_own_tokens() {
+        let mut vault = Vault::new(EncryptionKey::new("passphrase").unwrap());
+        let sealed = vault.seal("⟦same⟧ ⟦same⟧").unwrap();
+        let (first, second) = sealed.split_once(' ').unwrap();
+        assert_ne!(first, second);
+        // Values that were not changed keep their tokens
+        assert_eq!(vault.seal("⟦same⟧ ⟦same⟧").unwrap(), sealed);
+        assert_eq!(vault.seal("⟦same⟧").unwrap(), first);
+    }
+
+    #[test]
+    fn unclosed_values_are_refused() {
+        let mut vault = Vault::new(EncryptionKey::new("passphrase").unwrap());
+        assert!(matches!(
+            vault.seal("one\n⟦two⟧\n⟦three\n"),
+            Err(EditorError::UnclosedSecret(3))
+        ));
+        assert!(vault.seal("one\\⟦ ⟧ two").is_ok());
+    }
+
+    #[test]
+    fn secrets_are_hidden_line_for_line() {
+        assert_eq!(hide_secrets("a \\⟦b⟧ ⟦c\nd\\⟧⟧ e"), "a \\⟦b⟧ ⟦*\n***⟧ e");
+    }
+}